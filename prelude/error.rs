@@ -0,0 +1,130 @@
+use crate::ValueComparison;
+use ethers::types::{Address, BlockId, H256, U256};
+use std::fmt;
+
+/// Failures of fetching and preprocessing circuit inputs that are not
+/// covered by the more specific header, preflight and trie proof errors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PreludeError {
+    /// A caller supplied block selector is malformed
+    InvalidBlockSelector(String),
+    /// The RPC has no block matching the selector
+    BlockNotFound(BlockId),
+    /// The RPC failed or returned incomplete data
+    Rpc(String),
+    /// The fetched data cannot be laid out as circuit inputs
+    Preprocess(String),
+    /// The Safe's version or singleton has no known storage layout
+    UnknownSafeLayout(String),
+    /// Public inputs of an aggregated proof are not laid out as expected
+    InvalidPublicInputs(String),
+}
+
+impl PreludeError {
+    pub(crate) fn rpc<E: fmt::Display>(err: E) -> Self {
+        PreludeError::Rpc(err.to_string())
+    }
+}
+
+impl fmt::Display for PreludeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreludeError::InvalidBlockSelector(err) => write!(f, "invalid block selector {}", err),
+            PreludeError::BlockNotFound(block) => write!(f, "no such block {:?}", block),
+            PreludeError::Rpc(err) => write!(f, "rpc failed: {}", err),
+            PreludeError::Preprocess(err) => write!(f, "preprocessing failed: {}", err),
+            PreludeError::UnknownSafeLayout(err) => write!(f, "unknown safe layout: {}", err),
+            PreludeError::InvalidPublicInputs(err) => write!(f, "invalid public inputs: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PreludeError {}
+
+/// The Safe has not signed the message as of the given block, i.e. its
+/// signedMessages entry is zero and eth_getProof returned an exclusion proof
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageNotSigned {
+    pub safe: Address,
+    pub msg_hash: H256,
+    pub block: u64,
+}
+
+impl fmt::Display for MessageNotSigned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "safe {:?} has not signed msg hash {:?} as of block {}",
+            self.safe, self.msg_hash, self.block
+        )
+    }
+}
+
+impl std::error::Error for MessageNotSigned {}
+
+/// The address is not an owner of the Safe as of the given block, i.e. its
+/// entry in the owners linked list is zero or it is the list's sentinel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotAnOwner {
+    pub safe: Address,
+    pub owner: Address,
+    pub block: u64,
+}
+
+impl fmt::Display for NotAnOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} is not an owner of safe {:?} as of block {}",
+            self.owner, self.safe, self.block
+        )
+    }
+}
+
+impl std::error::Error for NotAnOwner {}
+
+/// The storage value does not satisfy the claimed comparison as of the given
+/// block, e.g. the Safe's threshold is below the bound
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ComparisonFailed {
+    pub contract: Address,
+    pub storage_key: H256,
+    pub value: U256,
+    pub comparison: ValueComparison,
+    pub block: u64,
+}
+
+impl fmt::Display for ComparisonFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "storage value {} of {:?} at key {:?} is not {} as of block {}",
+            self.value, self.contract, self.storage_key, self.comparison, self.block
+        )
+    }
+}
+
+impl std::error::Error for ComparisonFailed {}
+
+/// A public input of an aggregated proof differs from the value recomputed
+/// from the request and the anchor header, i.e. the proof attests to
+/// something other than what was asked for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BindingMismatch {
+    /// blockhash or challenge
+    pub input: &'static str,
+    pub expected: H256,
+    pub actual: H256,
+}
+
+impl fmt::Display for BindingMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "aggregated {} {:?} does not match the expected {:?}",
+            self.input, self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for BindingMismatch {}
@@ -0,0 +1,419 @@
+use crate::{keccak256, HEADER_RLP_MAX_BYTES, HEADER_RLP_MIN_BYTES};
+use ethers::types::{Block, H256};
+use rlp::RlpStream;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Hardforks that changed the set of header fields
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Hardfork {
+    /// 16 fields, adds base_fee_per_gas
+    London,
+    /// 17 fields, adds withdrawals_root
+    Shanghai,
+    /// 20 fields, adds blob_gas_used, excess_blob_gas, parent_beacon_block_root
+    Cancun,
+    /// 21 fields, adds requests_hash; no later fork has touched the header yet
+    Prague,
+}
+
+impl Hardfork {
+    /// Infers the most recent hardfork whose header fields the block carries
+    pub fn detect(block: &Block<H256>) -> Result<Self, HeaderError> {
+        if requests_hash(block).is_some() {
+            Ok(Hardfork::Prague)
+        } else if block.parent_beacon_block_root.is_some() {
+            Ok(Hardfork::Cancun)
+        } else if block.withdrawals_root.is_some() {
+            Ok(Hardfork::Shanghai)
+        } else if block.base_fee_per_gas.is_some() {
+            Ok(Hardfork::London)
+        } else {
+            Err(HeaderError::PreLondon)
+        }
+    }
+
+    /// Number of fields in the RLP list of a header of this fork
+    pub fn field_count(self) -> usize {
+        match self {
+            Hardfork::London => 16,
+            Hardfork::Shanghai => 17,
+            Hardfork::Cancun => 20,
+            Hardfork::Prague => 21,
+        }
+    }
+}
+
+impl fmt::Display for Hardfork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hardfork::London => write!(f, "london"),
+            Hardfork::Shanghai => write!(f, "shanghai"),
+            Hardfork::Cancun => write!(f, "cancun"),
+            Hardfork::Prague => write!(f, "prague"),
+        }
+    }
+}
+
+/// Activation of a hardfork at a block number (pre-merge forks) or a
+/// timestamp (Shanghai onwards)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForkActivation {
+    pub fork: Hardfork,
+    #[serde(default)]
+    pub block: Option<u64>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+impl ForkActivation {
+    fn is_active(&self, block: &Block<H256>) -> bool {
+        let number = block.number.map(|n| n.as_u64()).unwrap_or_default();
+        self.block.is_some_and(|b| b <= number)
+            || self
+                .timestamp
+                .is_some_and(|t| t <= block.timestamp.as_u64())
+    }
+}
+
+/// Per-chain hardfork schedule; an empty schedule falls back to detecting
+/// the fork from the fields the block carries
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ForkSchedule(pub Vec<ForkActivation>);
+
+impl ForkSchedule {
+    /// Latest hardfork active at the given block
+    pub fn fork_at(&self, block: &Block<H256>) -> Result<Hardfork, HeaderError> {
+        if self.0.is_empty() {
+            return Hardfork::detect(block);
+        }
+        self.0
+            .iter()
+            .filter(|a| a.is_active(block))
+            .map(|a| a.fork)
+            .max()
+            .ok_or(HeaderError::PreLondon)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// Blocks before London lack base_fee_per_gas and are not supported
+    PreLondon,
+    /// The block lacks a field required by the header layout of the given fork
+    MissingField { fork: Hardfork, field: &'static str },
+    /// The encoded header does not fit the bounds of the anchor circuit
+    Length { len: usize, min: usize, max: usize },
+    /// keccak256 of the encoded header differs from the hash reported by the RPC
+    HashMismatch { expected: H256, actual: H256 },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::PreLondon => write!(f, "pre-london headers are not supported"),
+            HeaderError::MissingField { fork, field } => {
+                write!(f, "{} header is missing field {}", fork, field)
+            }
+            HeaderError::Length { len, min, max } => write!(
+                f,
+                "header rlp length {} out of circuit bounds [{}, {}]",
+                len, min, max
+            ),
+            HeaderError::HashMismatch { expected, actual } => write!(
+                f,
+                "header hash mismatch: expected {:?} got {:?}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// EIP-7685 requests hash, not yet modelled by ethers' Block
+fn requests_hash(block: &Block<H256>) -> Option<H256> {
+    block
+        .other
+        .get_deserialized::<H256>("requestsHash")
+        .and_then(|h| h.ok())
+}
+
+fn required<T>(fork: Hardfork, field: &'static str, value: Option<T>) -> Result<T, HeaderError> {
+    value.ok_or(HeaderError::MissingField { fork, field })
+}
+
+/// RLP-encodes a header with the field set of the block's detected hardfork
+pub fn rlp_encode_header(
+    block: &Block<H256>,
+) -> Result<(usize, [u8; HEADER_RLP_MAX_BYTES]), HeaderError> {
+    rlp_encode_header_for_fork(block, Hardfork::detect(block)?)
+}
+
+/// Like `rlp_encode_header_unchecked` but also checks the hash of the
+/// encoding against block.hash
+pub fn rlp_encode_header_for_fork(
+    block: &Block<H256>,
+    fork: Hardfork,
+) -> Result<(usize, [u8; HEADER_RLP_MAX_BYTES]), HeaderError> {
+    let (len, header_rlp) = rlp_encode_header_unchecked(block, fork)?;
+    if let Some(expected) = block.hash {
+        let actual = H256(keccak256(&header_rlp[..len]));
+        if actual != expected {
+            return Err(HeaderError::HashMismatch { expected, actual });
+        }
+    }
+    Ok((len, header_rlp))
+}
+
+/// RLP-encodes a header with the field set of the given hardfork and
+/// right-pads it with 0xff to HEADER_RLP_MAX_BYTES, leaving the hash check to
+/// the caller
+// https://ethereum.stackexchange.com/a/67332
+// https://github.com/ethereum/go-ethereum/blob/14eb8967be7acc54c5dc9a416151ac45c01251b6/core/types/block.go#L65
+pub fn rlp_encode_header_unchecked(
+    block: &Block<H256>,
+    fork: Hardfork,
+) -> Result<(usize, [u8; HEADER_RLP_MAX_BYTES]), HeaderError> {
+    let mut rlp = RlpStream::new();
+    rlp.begin_list(fork.field_count());
+    rlp.append(&block.parent_hash);
+    rlp.append(&block.uncles_hash);
+    rlp.append(&required(fork, "author", block.author)?);
+    rlp.append(&block.state_root);
+    rlp.append(&block.transactions_root);
+    rlp.append(&block.receipts_root);
+    rlp.append(&required(fork, "logs_bloom", block.logs_bloom)?);
+    rlp.append(&block.difficulty);
+    rlp.append(&required(fork, "number", block.number)?);
+    rlp.append(&block.gas_limit);
+    rlp.append(&block.gas_used);
+    rlp.append(&block.timestamp);
+    rlp.append(&block.extra_data.to_vec());
+    rlp.append(&required(fork, "mix_hash", block.mix_hash)?);
+    rlp.append(&required(fork, "nonce", block.nonce)?);
+    rlp.append(&required(fork, "base_fee_per_gas", block.base_fee_per_gas)?);
+    if fork >= Hardfork::Shanghai {
+        rlp.append(&required(fork, "withdrawals_root", block.withdrawals_root)?);
+    }
+    if fork >= Hardfork::Cancun {
+        rlp.append(&required(fork, "blob_gas_used", block.blob_gas_used)?);
+        rlp.append(&required(fork, "excess_blob_gas", block.excess_blob_gas)?);
+        rlp.append(&required(
+            fork,
+            "parent_beacon_block_root",
+            block.parent_beacon_block_root,
+        )?);
+    }
+    if fork >= Hardfork::Prague {
+        rlp.append(&required(fork, "requests_hash", requests_hash(block))?);
+    }
+    let mut bytes: Vec<u8> = rlp.out().freeze().into();
+
+    let unpadded_length = bytes.len();
+    if !(HEADER_RLP_MIN_BYTES..=HEADER_RLP_MAX_BYTES).contains(&unpadded_length) {
+        return Err(HeaderError::Length {
+            len: unpadded_length,
+            min: HEADER_RLP_MIN_BYTES,
+            max: HEADER_RLP_MAX_BYTES,
+        });
+    }
+
+    bytes.resize(HEADER_RLP_MAX_BYTES, 0xff);
+
+    Ok((
+        unpadded_length,
+        bytes
+            .try_into()
+            .expect("header_rlp has HEADER_RLP_MAX_BYTES"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlp::Rlp;
+    use std::{fs::read_to_string, path::Path};
+
+    /// EIP-7685 requests hash of a block without requests, i.e. sha256("")
+    const EMPTY_REQUESTS_HASH: &str =
+        "0xe3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    /// Cancun block 35591564 on Gnosis
+    fn cancun_block() -> Block<H256> {
+        serde_json::from_str(include_str!("fixtures/headers/gnosis_35591564.json")).unwrap()
+    }
+
+    fn header_items(header_rlp: &[u8]) -> Vec<Vec<u8>> {
+        Rlp::new(header_rlp)
+            .iter()
+            .map(|item| item.as_raw().to_vec())
+            .collect()
+    }
+
+    /// Synthetic block: the Cancun block as if produced under `fork` with the
+    /// given extra data, i.e. without the fields later forks added or, for
+    /// Prague, with a requests hash. Its hash is that of the real header's
+    /// RLP items cut or extended the same way, so it pins the field layout
+    /// of each fork but is no on-chain hash; `header_fixtures` checks those.
+    fn block_for_fork(fork: Hardfork, extra_data: &[u8]) -> Block<H256> {
+        let mut block = cancun_block();
+        let (len, header_rlp) = rlp_encode_header(&block).unwrap();
+        let mut items = header_items(&header_rlp[..len]);
+        items.truncate(fork.field_count());
+        items[12] = rlp::encode(&extra_data.to_vec()).to_vec();
+        block.extra_data = extra_data.to_vec().into();
+        if fork < Hardfork::Cancun {
+            block.blob_gas_used = None;
+            block.excess_blob_gas = None;
+            block.parent_beacon_block_root = None;
+        }
+        if fork < Hardfork::Shanghai {
+            block.withdrawals_root = None;
+        }
+        if fork == Hardfork::Prague {
+            let requests_hash: H256 = EMPTY_REQUESTS_HASH.parse().unwrap();
+            block
+                .other
+                .insert("requestsHash".to_string(), EMPTY_REQUESTS_HASH.into());
+            items.push(rlp::encode(&requests_hash).to_vec());
+        }
+        let mut rlp = RlpStream::new_list(items.len());
+        for item in &items {
+            rlp.append_raw(item, 1);
+        }
+        block.hash = Some(H256(keccak256(rlp.out())));
+        block
+    }
+
+    #[test]
+    fn cancun_vector() {
+        let block = cancun_block();
+        assert_eq!(Hardfork::detect(&block), Ok(Hardfork::Cancun));
+        let (len, header_rlp) = rlp_encode_header(&block).unwrap();
+        assert_eq!(len, 590);
+        assert_eq!(
+            H256(keccak256(&header_rlp[..len])),
+            "0x1bc337a28fc630fbfe649bda4df9f978887c6028044af64432868c0640adb5fc"
+                .parse()
+                .unwrap()
+        );
+        assert_eq!(header_items(&header_rlp[..len]).len(), 20);
+        assert!(header_rlp[len..].iter().all(|b| *b == 0xff));
+    }
+
+    /// Blocks saved by scripts/header_fixture.sh, each encoded under the fork
+    /// it is detected as and checked against the hash the chain reports
+    #[test]
+    fn header_fixtures() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/headers");
+        let mut forks = Vec::new();
+        for entry in dir.read_dir().unwrap() {
+            let path = entry.unwrap().path();
+            let block: Block<H256> = serde_json::from_str(&read_to_string(&path).unwrap())
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+            let fork = Hardfork::detect(&block).unwrap();
+            let (len, header_rlp) = rlp_encode_header(&block)
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+            assert_eq!(H256(keccak256(&header_rlp[..len])), block.hash.unwrap());
+            assert_eq!(header_items(&header_rlp[..len]).len(), fork.field_count());
+            forks.push(fork);
+        }
+        assert!(forks.contains(&Hardfork::Cancun));
+    }
+
+    #[test]
+    fn synthetic_fork_layouts() {
+        let forks = [
+            (Hardfork::London, 64),
+            (Hardfork::Shanghai, 32),
+            (Hardfork::Cancun, 10),
+            (Hardfork::Prague, 10),
+        ];
+        for (fork, extra_data_len) in forks {
+            let block = block_for_fork(fork, &vec![0xab; extra_data_len]);
+            assert_eq!(Hardfork::detect(&block), Ok(fork));
+            let (len, header_rlp) = rlp_encode_header(&block).unwrap();
+            assert_eq!(H256(keccak256(&header_rlp[..len])), block.hash.unwrap());
+            assert_eq!(header_items(&header_rlp[..len]).len(), fork.field_count());
+            // Omitting fields of the block's fork breaks the hash
+            for earlier in forks.iter().map(|(f, _)| *f).filter(|f| *f < fork) {
+                assert!(matches!(
+                    rlp_encode_header_for_fork(&block, earlier),
+                    Err(HeaderError::HashMismatch { .. }) | Err(HeaderError::Length { .. })
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn missing_fields() {
+        let london = block_for_fork(Hardfork::London, &[0xab; 64]);
+        assert_eq!(
+            rlp_encode_header_for_fork(&london, Hardfork::Shanghai),
+            Err(HeaderError::MissingField {
+                fork: Hardfork::Shanghai,
+                field: "withdrawals_root"
+            })
+        );
+        assert_eq!(
+            rlp_encode_header_for_fork(&cancun_block(), Hardfork::Prague),
+            Err(HeaderError::MissingField {
+                fork: Hardfork::Prague,
+                field: "requests_hash"
+            })
+        );
+        let mut pre_london = london;
+        pre_london.base_fee_per_gas = None;
+        assert_eq!(Hardfork::detect(&pre_london), Err(HeaderError::PreLondon));
+    }
+
+    #[test]
+    fn length_bounds() {
+        let len = |fork, extra_data_len| {
+            let mut block = block_for_fork(fork, &vec![0xab; extra_data_len]);
+            block.hash = None;
+            rlp_encode_header(&block).map(|(len, _)| len)
+        };
+        let out_of_bounds = |len| {
+            Err(HeaderError::Length {
+                len,
+                min: HEADER_RLP_MIN_BYTES,
+                max: HEADER_RLP_MAX_BYTES,
+            })
+        };
+        assert_eq!(len(Hardfork::London, 63), out_of_bounds(576));
+        assert_eq!(len(Hardfork::London, 64), Ok(577));
+        assert_eq!(len(Hardfork::Prague, 23), Ok(636));
+        assert_eq!(len(Hardfork::Prague, 24), out_of_bounds(637));
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let mut block = cancun_block();
+        let expected = block.hash.unwrap();
+        block.state_root = H256::zero();
+        let Err(HeaderError::HashMismatch {
+            expected: reported,
+            actual,
+        }) = rlp_encode_header(&block)
+        else {
+            panic!("expected a hash mismatch");
+        };
+        assert_eq!(reported, expected);
+        assert_ne!(actual, expected);
+
+        let mut block = cancun_block();
+        let actual = block.hash.unwrap();
+        block.hash = Some(H256::zero());
+        assert_eq!(
+            rlp_encode_header(&block).map(|(len, _)| len),
+            Err(HeaderError::HashMismatch {
+                expected: H256::zero(),
+                actual
+            })
+        );
+    }
+}
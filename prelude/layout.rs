@@ -0,0 +1,176 @@
+use crate::{
+    PreludeError, SAFE_OWNERS_SLOT, SAFE_OWNER_COUNT_SLOT, SAFE_SIGNED_MESSAGES_SLOT,
+    SAFE_THRESHOLD_SLOT,
+};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Versions whose singletons, L2 variants included, share the 1.x layout:
+/// singleton, modules, owners, ownerCount, threshold, nonce, domainSeparator,
+/// signedMessages, approvedHashes from slot 0 onwards
+const V1_VERSIONS: &[&str] = &[
+    "1.0.0", "1.1.0", "1.1.1", "1.2.0", "1.3.0", "1.4.0", "1.4.1", "1.5.0",
+];
+
+/// Canonical singletons by version, to fall back on if VERSION() fails
+/// SEE https://github.com/safe-global/safe-deployments
+const KNOWN_SINGLETONS: &[(&str, &str)] = &[
+    ("0xb6029EA3B2c51D09a50B53CA8012FeEB05bDa35A", "1.0.0"),
+    ("0x34CfAC646f301356fAa8B21e94227e3583Fe3F5F", "1.1.1"),
+    ("0x6851D6fDFAfD08c0295C392436245E5bc78B0185", "1.2.0"),
+    ("0xd9Db270c1B5E3Bd161E8c8503c55cEABeE709552", "1.3.0"),
+    ("0x3E5c63644E683549055b9Be8653de26E0B4CD36E", "1.3.0"),
+    ("0x41675C099F32341bf84BFc5382aF534df5C7461a", "1.4.1"),
+    ("0x29fcB43b46531BcA003ddC8FCB67FFE91900C762", "1.4.1"),
+];
+
+/// Storage slots of the Safe variables the prelude proves
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafeLayout {
+    pub owners_slot: [u8; 32],
+    pub owner_count_slot: [u8; 32],
+    pub threshold_slot: [u8; 32],
+    pub signed_messages_slot: [u8; 32],
+}
+
+impl Default for SafeLayout {
+    /// The layout of Safe 1.x singletons behind a proxy
+    fn default() -> Self {
+        SafeLayout {
+            owners_slot: SAFE_OWNERS_SLOT,
+            owner_count_slot: SAFE_OWNER_COUNT_SLOT,
+            threshold_slot: SAFE_THRESHOLD_SLOT,
+            signed_messages_slot: SAFE_SIGNED_MESSAGES_SLOT,
+        }
+    }
+}
+
+impl SafeLayout {
+    /// Looks up the layout of a Safe version as returned by VERSION()
+    pub fn for_version(version: &str) -> Result<Self, PreludeError> {
+        if V1_VERSIONS.contains(&version) {
+            Ok(SafeLayout::default())
+        } else {
+            Err(PreludeError::UnknownSafeLayout(format!(
+                "version {}",
+                version
+            )))
+        }
+    }
+}
+
+/// Version and singleton of a Safe proxy at some block
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafeVersion {
+    /// Singleton the proxy delegates to, i.e. its slot 0
+    pub singleton: Address,
+    /// VERSION() of the singleton, if it has one
+    pub version: Option<String>,
+}
+
+impl SafeVersion {
+    /// No singleton at slot 0, i.e. there is no Safe at the address
+    pub fn is_absent(&self) -> bool {
+        self.singleton.is_zero()
+    }
+
+    /// Resolves the layout by version, or by singleton if VERSION() failed
+    pub fn layout(&self) -> Result<SafeLayout, PreludeError> {
+        if self.is_absent() {
+            return Err(PreludeError::UnknownSafeLayout(
+                "no singleton at slot 0".to_string(),
+            ));
+        }
+        if let Some(version) = &self.version {
+            return SafeLayout::for_version(version);
+        }
+        KNOWN_SINGLETONS
+            .iter()
+            .find(|(singleton, _)| Address::from_str(singleton).ok() == Some(self.singleton))
+            .map(|(_, version)| SafeLayout::for_version(version))
+            .unwrap_or_else(|| {
+                Err(PreludeError::UnknownSafeLayout(format!(
+                    "singleton {:?} without VERSION()",
+                    self.singleton
+                )))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unknown_layout(result: Result<SafeLayout, PreludeError>) -> String {
+        match result {
+            Err(PreludeError::UnknownSafeLayout(reason)) => reason,
+            other => panic!("expected an unknown layout got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn layout_by_version() {
+        for version in V1_VERSIONS {
+            assert_eq!(SafeLayout::for_version(version), Ok(SafeLayout::default()));
+        }
+        for version in ["0.1.0", "2.0.0", "1.3.0+L2", ""] {
+            assert_eq!(
+                unknown_layout(SafeLayout::for_version(version)),
+                format!("version {}", version)
+            );
+        }
+        let layout = SafeLayout::default();
+        assert_eq!(layout.signed_messages_slot[31], 7);
+        assert_eq!(layout.owners_slot[31], 2);
+        assert_eq!(layout.owner_count_slot[31], 3);
+        assert_eq!(layout.threshold_slot[31], 4);
+    }
+
+    #[test]
+    fn layout_by_known_singleton() {
+        for (singleton, _) in KNOWN_SINGLETONS {
+            let version = SafeVersion {
+                singleton: singleton.parse().unwrap(),
+                version: None,
+            };
+            assert_eq!(version.layout(), Ok(SafeLayout::default()));
+        }
+        // VERSION() takes precedence over the singleton
+        let version = SafeVersion {
+            singleton: KNOWN_SINGLETONS[0].0.parse().unwrap(),
+            version: Some("2.0.0".to_string()),
+        };
+        assert_eq!(unknown_layout(version.layout()), "version 2.0.0");
+    }
+
+    #[test]
+    fn unknown_singleton() {
+        let singleton = Address::repeat_byte(0x5a);
+        let version = SafeVersion {
+            singleton,
+            version: None,
+        };
+        assert!(!version.is_absent());
+        assert_eq!(
+            unknown_layout(version.layout()),
+            format!("singleton {:?} without VERSION()", singleton)
+        );
+        // custom singletons of a known version share its layout
+        let version = SafeVersion {
+            singleton,
+            version: Some("1.4.1".to_string()),
+        };
+        assert_eq!(version.layout(), Ok(SafeLayout::default()));
+    }
+
+    #[test]
+    fn absent_safe() {
+        let version = SafeVersion {
+            singleton: Address::zero(),
+            version: None,
+        };
+        assert!(version.is_absent());
+        assert_eq!(unknown_layout(version.layout()), "no singleton at slot 0");
+    }
+}
@@ -1,21 +1,63 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::Result;
 use ark_bn254::Fr;
 use ark_ff::{BigInteger, PrimeField};
 use const_hex::encode as hex;
 use ethers::{
+    abi::{decode, ParamType, Token},
     providers::{Middleware, Provider},
-    types::{Address, Block, Bytes, H256},
+    types::{
+        transaction::eip2718::TypedTransaction, Address, Block, Bytes, EIP1186ProofResponse,
+        TransactionRequest, U256,
+    },
 };
 use light_poseidon::{Poseidon, PoseidonHasher};
-use rlp::RlpStream;
 use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
 use tiny_keccak::{Hasher, Keccak};
-use zerocopy::AsBytes;
+
+mod error;
+mod header;
+mod layout;
+mod mpt;
+mod preflight;
+mod public_inputs;
+mod slot;
+
+pub use error::{BindingMismatch, ComparisonFailed, MessageNotSigned, NotAnOwner, PreludeError};
+pub use ethers::types::{BlockId, BlockNumber, H256};
+pub use header::{
+    rlp_encode_header, rlp_encode_header_for_fork, rlp_encode_header_unchecked, ForkActivation,
+    ForkSchedule, Hardfork, HeaderError,
+};
+pub use layout::{SafeLayout, SafeVersion};
+pub use mpt::{resolve, MptError, EMPTY_TRIE_ROOT};
+pub use preflight::{preflight, verify_inputs, PreflightError};
+pub use public_inputs::{
+    AggregatedPublicInputs, ACCUMULATOR_LIMBS, AGGREGATED_PUBLIC_INPUTS_BYTES,
+};
+pub use slot::{Comparison, MappingKey, SafeSetting, StorageQuery, ValueComparison};
 
 /// NOTE Since Safes have proxies the actual storage slot of the signed_messages mapping is 5+2
+/// in the layout of Safe 1.x, see `SafeLayout` for other versions
 pub const SAFE_SIGNED_MESSAGES_SLOT: [u8; 32] = [
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7,
 ];
+/// Storage slot of the owners mapping, i.e. the linked list of Safe owners
+pub const SAFE_OWNERS_SLOT: [u8; 32] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2,
+];
+/// Storage slot of the Safe's ownerCount
+pub const SAFE_OWNER_COUNT_SLOT: [u8; 32] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3,
+];
+/// Storage slot of the Safe's threshold
+pub const SAFE_THRESHOLD_SLOT: [u8; 32] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4,
+];
+/// Head of the owners linked list which is not an owner itself
+/// SEE https://github.com/safe-global/safe-smart-account/blob/bf943f80fec5ac647159d26161446ac5d716a294/contracts/base/OwnerManager.sol#L18
+pub const SAFE_SENTINEL_OWNERS: [u8; 20] =
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
 /// SEE https://github.com/safe-global/safe-smart-account/blob/bf943f80fec5ac647159d26161446ac5d716a294/contracts/libraries/SignMessageLib.sol#L24
 pub const SAFE_SIGNED_MSG_VALUE: [u8; 32] = [
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
@@ -38,11 +80,13 @@ const HEADER_RLP_MAX_BYTES: usize = 636;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Inputs {
-    pub safe_address: [u8; 20],     // Safe address
-    pub msg_hash: [u8; 32],         // Custom msg hash
-    pub state_root: [u8; 32],       // eth_getBlockBy*::stateRoot
-    pub storage_root: [u8; 32],     // eth_getProof::storageHash
-    pub storage_key: [u8; 32],      // keccak256(msg_hash + uint256(7))
+    pub safe_address: [u8; 20], // Safe address or contract in storage mode
+    pub msg_hash: [u8; 32],     // Custom msg hash or StorageQuery::label()
+    pub state_root: [u8; 32],   // eth_getBlockBy*::stateRoot
+    pub storage_root: [u8; 32], // eth_getProof::storageHash
+    pub storage_key: [u8; 32],  // keccak256(msg_hash + uint256(7))
+    #[serde(default = "safe_signed_msg_value")]
+    pub storage_value: [u8; 32], // eth_getProof::storageProof.value
     pub account_proof_depth: usize, // eth_getProof::accountProof.len()
     pub storage_proof_depth: usize, // eth_getProof::storageProof.proof.len()
     #[serde(with = "serde_arrays")]
@@ -56,6 +100,13 @@ pub struct Inputs {
     pub header_rlp_len: usize, // Length of the unpadded header
 }
 
+impl Inputs {
+    /// The unpadded RLP-encoded header, i.e. the preimage of the blockhash
+    pub fn header(&self) -> &[u8] {
+        &self.header_rlp[..self.header_rlp_len.min(HEADER_RLP_MAX_BYTES)]
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct InputsFe {
     // These are all 0xhex strings of bn254 field elements
@@ -66,13 +117,15 @@ pub struct InputsFe {
     pub storage_root_fe: String, // eth_getProof::storageHash
     pub storage_key_fe: String, // keccak256(msg_hash + uint256(7))
     // precalculated outputs
-    pub blockhash: String,          // keccak256(header_rlp)
-    pub challenge: String,          // poseidon(safe_address, msg_hash)
-    pub safe_address: [u8; 20],     // Safe address
-    pub msg_hash: [u8; 32],         // Custom msg hash
-    pub state_root: [u8; 32],       // eth_getBlockBy*::stateRoot
-    pub storage_root: [u8; 32],     // eth_getProof::storageHash
-    pub storage_key: [u8; 32],      // keccak256(msg_hash + uint256(7))
+    pub blockhash: String,      // keccak256(header_rlp)
+    pub challenge: String,      // poseidon(safe_address, msg_hash)
+    pub safe_address: [u8; 20], // Safe address
+    pub msg_hash: [u8; 32],     // Custom msg hash
+    pub state_root: [u8; 32],   // eth_getBlockBy*::stateRoot
+    pub storage_root: [u8; 32], // eth_getProof::storageHash
+    pub storage_key: [u8; 32],  // keccak256(msg_hash + uint256(7))
+    #[serde(default = "safe_signed_msg_value")]
+    pub storage_value: [u8; 32], // eth_getProof::storageProof.value
     pub account_proof_depth: usize, // eth_getProof::accountProof.len()
     pub storage_proof_depth: usize, // eth_getProof::storageProof.proof.len()
     #[serde(with = "serde_arrays")]
@@ -84,23 +137,84 @@ pub struct InputsFe {
     #[serde(with = "serde_arrays")]
     pub header_rlp: [u8; HEADER_RLP_MAX_BYTES], // RLP-encoded header
     pub header_rlp_len: usize, // Length of the unpadded header
+    // Absent from v1 inputs so their prover files stay as they were
+    #[serde(default, skip_serializing_if = "InputFormat::is_v1")]
+    pub input_format: InputFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_hash_hi: Option<String>, // Upper 128 bits of msg_hash in v2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_hash_lo: Option<String>, // Lower 128 bits of msg_hash in v2
+}
+
+/// Versions of how the circuit inputs encode the message hash
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    /// msg_hash as one field element, reduced if it exceeds the scalar
+    /// field, and challenge = poseidon(safe_address, msg_hash)
+    #[default]
+    V1,
+    /// msg_hash as two 128-bit limbs, which no two message hashes share, and
+    /// challenge = poseidon(safe_address, msg_hash_hi, msg_hash_lo)
+    V2,
+}
+
+impl InputFormat {
+    pub fn is_v1(&self) -> bool {
+        *self == InputFormat::V1
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(InputFormat::V1),
+            "v2" => Ok(InputFormat::V2),
+            _ => Err(format!("unknown input format {}, expected v1 or v2", s)),
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputFormat::V1 => write!(f, "v1"),
+            InputFormat::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+/// Inputs saved before the storage value was part of them are all of Safe
+/// signedMessages entries
+fn safe_signed_msg_value() -> [u8; 32] {
+    SAFE_SIGNED_MSG_VALUE
 }
 
 impl From<Inputs> for InputsFe {
     fn from(inputs: Inputs) -> Self {
-        let trimmed_header = trim_header(inputs.header_rlp);
-        let blockhash = Fr::from_be_bytes_mod_order(&keccak256(trimmed_header));
-
-        let mut poseidon_h2 = Poseidon::<Fr>::new_circom(2).expect("poseidon hash2 init failed");
-        // _mod_order might reduce msg_hash_fe i.e. it has 2 preimages aka collision;
-        // since the 20-byte Safe address cannot exceed bn254's scalar field _mod_order
-        // is always a noop for safe_address_fe, i.e. it has strictly 1 address preimage:
-        // no collisions; consequently "cross-account" collisions can never occur
+        InputsFe::new(inputs, InputFormat::V1)
+    }
+}
+
+impl InputsFe {
+    /// Encodes the inputs as field elements in the given format
+    pub fn new(inputs: Inputs, input_format: InputFormat) -> Self {
+        let blockhash = blockhash_fe(inputs.header());
         let safe_address_fe = Fr::from_be_bytes_mod_order(&lpad_bytes32(&inputs.safe_address));
         let msg_hash_fe = Fr::from_be_bytes_mod_order(&inputs.msg_hash);
-        let challenge = poseidon_h2
-            .hash(&[safe_address_fe, msg_hash_fe])
-            .expect("poseidon hash failed");
+        let challenge = challenge_fe(&inputs.safe_address, &inputs.msg_hash, input_format);
+        let (msg_hash_hi, msg_hash_lo) = match input_format {
+            InputFormat::V1 => (None, None),
+            InputFormat::V2 => {
+                let (hi, lo) = msg_hash_limbs(&inputs.msg_hash);
+                (
+                    Some(format!("0x{}", hex(hi.into_bigint().to_bytes_be()))),
+                    Some(format!("0x{}", hex(lo.into_bigint().to_bytes_be()))),
+                )
+            }
+        };
 
         let state_root_fe = Fr::from_be_bytes_mod_order(&inputs.state_root);
         let storage_root_fe = Fr::from_be_bytes_mod_order(&inputs.storage_root);
@@ -128,6 +242,7 @@ impl From<Inputs> for InputsFe {
             state_root: inputs.state_root,
             storage_root: inputs.storage_root,
             storage_key: inputs.storage_key,
+            storage_value: inputs.storage_value,
             account_proof_depth: inputs.account_proof_depth,
             storage_proof_depth: inputs.storage_proof_depth,
             padded_account_value: inputs.padded_account_value,
@@ -137,130 +252,706 @@ impl From<Inputs> for InputsFe {
             header_rlp_len: inputs.header_rlp_len,
             blockhash: format!("0x{}", hex(blockhash.into_bigint().to_bytes_be())),
             challenge: format!("0x{}", hex(challenge.into_bigint().to_bytes_be())),
+            input_format,
+            msg_hash_hi,
+            msg_hash_lo,
         }
     }
 }
 
+/// keccak256 of a header reduced into the scalar field, as the anchor circuit
+/// outputs it
+pub(crate) fn blockhash_fe(header_rlp: &[u8]) -> Fr {
+    Fr::from_be_bytes_mod_order(&keccak256(header_rlp))
+}
+
+/// The challenge of a Safe and message, as the anchor circuit outputs it
+pub(crate) fn challenge_fe(
+    safe_address: &[u8; 20],
+    msg_hash: &[u8; 32],
+    input_format: InputFormat,
+) -> Fr {
+    // since the 20-byte Safe address cannot exceed bn254's scalar field _mod_order
+    // is always a noop for safe_address_fe, i.e. it has strictly 1 address preimage:
+    // no collisions; consequently "cross-account" collisions can never occur
+    let safe_address_fe = Fr::from_be_bytes_mod_order(&lpad_bytes32(safe_address));
+    match input_format {
+        InputFormat::V1 => {
+            // _mod_order might reduce msg_hash_fe i.e. it has 2 preimages aka collision
+            let msg_hash_fe = Fr::from_be_bytes_mod_order(msg_hash);
+            let mut poseidon_h2 =
+                Poseidon::<Fr>::new_circom(2).expect("poseidon hash2 init failed");
+            poseidon_h2
+                .hash(&[safe_address_fe, msg_hash_fe])
+                .expect("poseidon hash failed")
+        }
+        InputFormat::V2 => {
+            // 128-bit limbs are never reduced so each challenge has 1 msg_hash preimage
+            let (hi, lo) = msg_hash_limbs(msg_hash);
+            let mut poseidon_h3 =
+                Poseidon::<Fr>::new_circom(3).expect("poseidon hash3 init failed");
+            poseidon_h3
+                .hash(&[safe_address_fe, hi, lo])
+                .expect("poseidon hash failed")
+        }
+    }
+}
+
+/// Upper and lower 128 bits of a message hash as field elements
+fn msg_hash_limbs(msg_hash: &[u8; 32]) -> (Fr, Fr) {
+    (
+        Fr::from_be_bytes_mod_order(&msg_hash[..16]),
+        Fr::from_be_bytes_mod_order(&msg_hash[16..]),
+    )
+}
+
+/// Chain specific parameters of input fetching
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainSpec {
+    /// Hardfork schedule determining the header layout
+    pub forks: ForkSchedule,
+    /// Storage slots of the Safe's variables
+    pub layout: SafeLayout,
+}
+
+/// Parses a block selector: a decimal or 0x-hex block number, a 0x-prefixed
+/// 32-byte block hash or one of the tags `latest`, `safe`, `finalized`
+pub fn parse_block_selector(s: &str) -> Result<BlockId> {
+    Ok(BlockId::from_str(s.trim())
+        .map_err(|e| PreludeError::InvalidBlockSelector(format!("{}: {}", s, e)))?)
+}
+
+/// Resolves a block selector to the number of the block it currently points at
+pub async fn fetch_block_number(rpc: &str, block: BlockId) -> Result<u64> {
+    let provider = Provider::try_from(rpc).map_err(PreludeError::rpc)?;
+    let block = provider
+        .get_block(block)
+        .await
+        .map_err(PreludeError::rpc)?
+        .ok_or(PreludeError::BlockNotFound(block))?;
+    Ok(block
+        .number
+        .ok_or(PreludeError::rpc("pending block"))?
+        .as_u64())
+}
+
+/// Pins a block tag like latest to the number it currently points at, so
+/// what is fetched for it in several calls is of the same block
+pub async fn pin_block(rpc: &str, block: BlockId) -> Result<BlockId> {
+    match block {
+        BlockId::Hash(_) | BlockId::Number(BlockNumber::Number(_)) => Ok(block),
+        _ => Ok(fetch_block_number(rpc, block).await?.into()),
+    }
+}
+
+/// Detects the storage layout of a Safe at a pinned block. An address
+/// without a Safe has not signed anything, which any layout tells, so it
+/// gets the 1.x layout and unsigned messages are reported as such.
+pub async fn detect_safe_layout(
+    rpc: &str,
+    safe_address: Address,
+    block: BlockId,
+) -> Result<SafeLayout> {
+    let version = detect_safe_version(rpc, safe_address, block).await?;
+    if version.is_absent() {
+        return Ok(SafeLayout::default());
+    }
+    Ok(version.layout()?)
+}
+
+/// Reads the singleton and VERSION() of a Safe proxy at the given block to
+/// tell its storage layout, see `SafeVersion::layout`
+pub async fn detect_safe_version(
+    rpc: &str,
+    safe_address: Address,
+    block: BlockId,
+) -> Result<SafeVersion> {
+    let provider = Provider::try_from(rpc).map_err(PreludeError::rpc)?;
+    let slot = provider
+        .get_storage_at(safe_address, H256::zero(), Some(block))
+        .await
+        .map_err(PreludeError::rpc)?;
+    let singleton = Address::from(slot);
+    // Custom singletons might lack VERSION() so a failing call is no error
+    let call: TypedTransaction = TransactionRequest::new()
+        .to(safe_address)
+        .data(keccak256("VERSION()")[..4].to_vec())
+        .into();
+    let version = provider
+        .call(&call, Some(block))
+        .await
+        .ok()
+        .and_then(|output| decode(&[ParamType::String], &output).ok())
+        .and_then(|tokens| tokens.into_iter().next())
+        .and_then(Token::into_string);
+    Ok(SafeVersion { singleton, version })
+}
+
+/// Everything fetched from the RPC that inputs are built from; serializes to
+/// self-contained JSON that can be replayed without an RPC
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub chain_id: u64,
+    pub safe_address: Address,
+    pub msg_hashes: Vec<H256>,
+    /// eth_getBlockBy*
+    pub block: Block<H256>,
+    /// eth_getProof at the block with one storage proof per message hash
+    pub proof: EIP1186ProofResponse,
+    /// Layout the storage keys were derived with; snapshots predating it are
+    /// of Safe 1.x
+    #[serde(default)]
+    pub layout: SafeLayout,
+}
+
+/// Fetches the block and, in a single eth_getProof call, the EIP-1186 proofs
+/// of the Safe's signedMessages entries of all given message hashes
+pub async fn fetch_snapshot(
+    rpc: &str,
+    safe_address: Address,
+    msg_hashes: &[H256],
+    block: BlockId,
+    spec: &ChainSpec,
+) -> Result<Snapshot> {
+    let storage_keys = msg_hashes
+        .iter()
+        .map(|msg_hash| {
+            H256(StorageQuery::safe_signed_message(safe_address, *msg_hash, spec).storage_key())
+        })
+        .collect();
+    let (chain_id, block, proof) = fetch_proof(rpc, safe_address, storage_keys, block).await?;
+
+    Ok(Snapshot {
+        chain_id,
+        safe_address,
+        msg_hashes: msg_hashes.to_vec(),
+        block,
+        proof,
+        layout: spec.layout,
+    })
+}
+
+/// Fetches the chain id, the block and the EIP-1186 proof of the given
+/// storage keys of a contract at that block
+async fn fetch_proof(
+    rpc: &str,
+    contract: Address,
+    storage_keys: Vec<H256>,
+    block: BlockId,
+) -> Result<(u64, Block<H256>, EIP1186ProofResponse)> {
+    let provider = Provider::try_from(rpc).map_err(PreludeError::rpc)?;
+    let chain_id = provider.get_chainid().await.map_err(PreludeError::rpc)?;
+    let block = provider
+        .get_block(block)
+        .await
+        .map_err(PreludeError::rpc)?
+        .ok_or(PreludeError::BlockNotFound(block))?;
+    let anchor = block.number.ok_or(PreludeError::rpc("pending block"))?;
+    // Tags might advance between calls so pin the proof to the resolved block number
+    let proof = provider
+        .get_proof(contract, storage_keys, Some(anchor.into()))
+        .await
+        .map_err(PreludeError::rpc)?;
+    Ok((chain_id.as_u64(), block, proof))
+}
+
 pub async fn fetch_inputs(
     rpc: &str,
     safe_address: Address,
     msg_hash: H256,
+    block: BlockId,
+    spec: &ChainSpec,
 ) -> Result<(u64, Inputs)> {
-    let storage_key = keccak256(&concat_bytes64(msg_hash.into(), SAFE_SIGNED_MESSAGES_SLOT));
+    let snapshot = fetch_snapshot(rpc, safe_address, &[msg_hash], block, spec).await?;
+    build_inputs(
+        &snapshot.block,
+        &snapshot.proof,
+        safe_address,
+        msg_hash,
+        spec,
+    )
+}
 
-    let provider = Provider::try_from(rpc)?;
-    let latest = provider.get_block_number().await?;
-    let block = provider.get_block(latest).await?.context("no such block")?;
-    let proof = provider
-        .get_proof(safe_address, vec![storage_key.into()], Some(latest.into()))
-        .await?;
+/// Fetches the inputs of several messages signed by the same Safe at the same
+/// block with one eth_getBlockBy* and one eth_getProof call. Returns the block
+/// number along with the inputs of each message in the order given; unsigned
+/// messages fail individually with `MessageNotSigned`.
+pub async fn fetch_batch_inputs(
+    rpc: &str,
+    safe_address: Address,
+    msg_hashes: &[H256],
+    block: BlockId,
+    spec: &ChainSpec,
+) -> Result<(u64, Vec<Result<Inputs>>)> {
+    let snapshot = fetch_snapshot(rpc, safe_address, msg_hashes, block, spec).await?;
+    build_batch_inputs(
+        &snapshot.block,
+        &snapshot.proof,
+        safe_address,
+        msg_hashes,
+        spec,
+    )
+}
+
+/// Fetches the inputs proving that an address is an owner of the Safe at the
+/// given block
+pub async fn fetch_owner_inputs(
+    rpc: &str,
+    safe_address: Address,
+    owner: Address,
+    block: BlockId,
+    spec: &ChainSpec,
+) -> Result<(u64, Inputs)> {
+    let query = StorageQuery::safe_owner(safe_address, owner, spec);
+    let storage_keys = vec![H256(query.storage_key())];
+    let (_, block, proof) = fetch_proof(rpc, safe_address, storage_keys, block).await?;
+    build_owner_inputs(&block, &proof, safe_address, owner, spec)
+}
+
+/// Fetches the inputs proving that a contract's storage value satisfies a
+/// comparison, e.g. that a Safe's threshold is at least 2
+pub async fn fetch_comparison_inputs(
+    rpc: &str,
+    query: &StorageQuery,
+    comparison: &ValueComparison,
+    block: BlockId,
+    spec: &ChainSpec,
+) -> Result<(u64, Inputs)> {
+    let storage_keys = vec![H256(query.storage_key())];
+    let (_, block, proof) = fetch_proof(rpc, query.contract, storage_keys, block).await?;
+    build_comparison_inputs(&block, &proof, query, comparison, spec)
+}
+
+/// Fetches the inputs proving that a contract's storage slot holds a value,
+/// e.g. an ERC-20 balance, rather than a Safe's signedMessages entry
+pub async fn fetch_storage_inputs(
+    rpc: &str,
+    query: &StorageQuery,
+    block: BlockId,
+    spec: &ChainSpec,
+) -> Result<(u64, Inputs)> {
+    let storage_keys = vec![H256(query.storage_key())];
+    let (_, block, proof) = fetch_proof(rpc, query.contract, storage_keys, block).await?;
+    build_storage_inputs(&block, &proof, query, spec)
+}
+
+/// The parts of the inputs shared by all messages of a Safe at a block
+struct AccountInputs {
+    block_number: u64,
+    header_rlp: [u8; HEADER_RLP_MAX_BYTES],
+    header_rlp_len: usize,
+    account_proof_depth: usize,
+    padded_account_value: [u8; MAX_ACCOUNT_STATE_LENGTH],
+    account_proof: [u8; MAX_TRIE_NODE_LENGTH * ACCOUNT_PROOF_MAX_DEPTH],
+}
+
+/// Encodes the header of `block` with the field set of the hardfork active at
+/// it under `spec`, padded as the anchor circuit takes it. Its hash is left to
+/// preflight to check.
+pub fn encode_anchor_header(
+    block: &Block<H256>,
+    spec: &ChainSpec,
+) -> Result<(usize, [u8; HEADER_RLP_MAX_BYTES])> {
+    Ok(rlp_encode_header_unchecked(
+        block,
+        spec.forks.fork_at(block)?,
+    )?)
+}
+
+fn build_account_inputs(
+    block: &Block<H256>,
+    (header_rlp_len, header_rlp): (usize, [u8; HEADER_RLP_MAX_BYTES]),
+    proof: &EIP1186ProofResponse,
+    safe_address: Address,
+) -> Result<AccountInputs> {
+    let anchor = block.number.ok_or(PreludeError::rpc("pending block"))?;
+    if proof.address != safe_address {
+        return Err(PreludeError::Preprocess(format!(
+            "proof is of {:?} instead of {:?}",
+            proof.address, safe_address
+        ))
+        .into());
+    }
 
     let account_value = rlp::Rlp::new(
         &proof
             .account_proof
             .last() // Terminal proof node
-            .ok_or(anyhow!("State proof empty"))?,
+            .ok_or(PreludeError::rpc("State proof empty"))?,
     ) // Proof should have been non-empty
-    .as_list::<Vec<u8>>()?
+    .as_list::<Vec<u8>>()
+    .map_err(|e| PreludeError::Preprocess(format!("terminal account node: {}", e)))?
     .last() // Extract value
-    .ok_or(anyhow!("RLP list empty"))?
+    .ok_or(PreludeError::Preprocess("RLP list empty".to_string()))?
     .to_vec();
 
-    let (header_rlp_len, header_rlp) = rlp_encode_header(&block);
+    // A header not hashing to the RPC's block hash is inconsistent chain data
+    preflight(
+        block.hash.ok_or(PreludeError::rpc("pending block"))?,
+        &header_rlp[..header_rlp_len],
+        block.state_root,
+        &proof.account_proof,
+        &account_value,
+        proof.storage_hash,
+    )?;
 
-    let TrieProof {
-        proof: padded_storage_proof,
-        value: _,
-        depth: storage_proof_depth,
-        key: _,
-    } = preprocess_proof(
-        &proof.storage_proof[0].proof,
-        storage_key.to_vec(),
-        SAFE_SIGNED_MSG_VALUE.to_vec(),
-        STORAGE_PROOF_MAX_DEPTH,
+    let account_trie_proof = preprocess_proof(
+        &proof.account_proof,
+        safe_address.as_bytes().to_vec(),
+        account_value,
+        ACCOUNT_PROOF_MAX_DEPTH,
         MAX_TRIE_NODE_LENGTH,
-        MAX_STORAGE_VALUE_LENGTH,
+        MAX_ACCOUNT_STATE_LENGTH,
     )
-    .map_err(|_| anyhow!("Preprocess storage proof"))?;
-
+    .map_err(|e| PreludeError::Preprocess(format!("account proof: {}", e)))?;
+    account_trie_proof.verify_state_root(block.state_root, ACCOUNT_PROOF_MAX_DEPTH)?;
     let TrieProof {
         proof: padded_account_proof,
         value: padded_account_value,
         depth: account_proof_depth,
-        key: _,
-    } = preprocess_proof(
+        ..
+    } = account_trie_proof;
+
+    Ok(AccountInputs {
+        block_number: anchor.as_u64(),
+        header_rlp,
+        header_rlp_len,
+        account_proof_depth,
+        padded_account_value: padded_account_value
+            .try_into()
+            .map_err(|_| PreludeError::Preprocess("padded account value".to_string()))?,
+        account_proof: padded_account_proof
+            .try_into()
+            .map_err(|_| PreludeError::Preprocess("padded account proof".to_string()))?,
+    })
+}
+
+fn build_message_inputs(
+    block: &Block<H256>,
+    proof: &EIP1186ProofResponse,
+    account: &AccountInputs,
+    msg_hash: H256,
+    spec: &ChainSpec,
+) -> Result<Inputs> {
+    let query = StorageQuery::safe_signed_message(proof.address, msg_hash, spec);
+    build_slot_inputs(block, proof, account, &query).map_err(|err| {
+        match err.downcast_ref::<MptError>() {
+            Some(MptError::Absent) => MessageNotSigned {
+                safe: proof.address,
+                msg_hash,
+                block: account.block_number,
+            }
+            .into(),
+            _ => err,
+        }
+    })
+}
+
+/// `MessageNotSigned` if the proofs show an empty signedMessages entry, i.e.
+/// the account proof resolves under the header's state root and the storage
+/// proof shows the key absent from the account's storage trie, which is empty
+/// if the account itself is absent. Checked ahead of the header and account
+/// preflight so that a Safe without an account at the block reports the
+/// message as unsigned rather than failing preflight, but never on the RPC's
+/// word alone as an unsigned message is not retried on another RPC.
+fn unsigned_message(
+    block: &Block<H256>,
+    proof: &EIP1186ProofResponse,
+    safe_address: Address,
+    msg_hash: H256,
+    spec: &ChainSpec,
+) -> Result<Option<MessageNotSigned>> {
+    let storage_key = StorageQuery::safe_signed_message(safe_address, msg_hash, spec).storage_key();
+    if proof.address != safe_address {
+        return Ok(None);
+    }
+    let Some(storage_proof) = proof
+        .storage_proof
+        .iter()
+        .find(|sp| sp.key == U256::from_big_endian(&storage_key))
+        .filter(|sp| sp.value.is_zero())
+    else {
+        return Ok(None);
+    };
+
+    let storage_root = match resolve(
+        block.state_root,
+        safe_address.as_bytes(),
         &proof.account_proof,
-        safe_address.as_bytes().to_vec(),
-        account_value,
-        ACCOUNT_PROOF_MAX_DEPTH,
+    ) {
+        Ok(account) => {
+            // Account RLP is [nonce, balance, storage_root, code_hash]
+            let actual = rlp::Rlp::new(&account)
+                .val_at::<H256>(2)
+                .map_err(|_| PreflightError::AccountRlp)?;
+            if actual != proof.storage_hash {
+                return Err(PreflightError::StorageHash {
+                    expected: proof.storage_hash,
+                    actual,
+                }
+                .into());
+            }
+            actual
+        }
+        Err(MptError::Absent) => H256(EMPTY_TRIE_ROOT),
+        Err(err) => return Err(err.into()),
+    };
+    match resolve(storage_root, &storage_key, &storage_proof.proof) {
+        Err(MptError::Absent) => {}
+        // The RPC reported an empty entry the proof shows is set
+        Ok(leaf) => {
+            return Err(MptError::ValueMismatch {
+                expected: Vec::new(),
+                actual: rlp::Rlp::new(&leaf).data().unwrap_or(&leaf).to_vec(),
+            }
+            .into())
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    let block = block.number.ok_or(PreludeError::rpc("pending block"))?;
+    Ok(Some(MessageNotSigned {
+        safe: safe_address,
+        msg_hash,
+        block: block.as_u64(),
+    }))
+}
+
+/// Fails with `MptError::Absent` if the slot is empty since the circuits
+/// cannot prove exclusion
+fn build_slot_inputs(
+    block: &Block<H256>,
+    proof: &EIP1186ProofResponse,
+    account: &AccountInputs,
+    query: &StorageQuery,
+) -> Result<Inputs> {
+    let storage_key = query.storage_key();
+    let storage_proof = proof
+        .storage_proof
+        .iter()
+        .find(|sp| sp.key == U256::from_big_endian(&storage_key))
+        .ok_or_else(|| {
+            PreludeError::Preprocess(format!("no storage proof of key {:?}", H256(storage_key)))
+        })?;
+
+    if storage_proof.value.is_zero() {
+        return Err(MptError::Absent.into());
+    }
+    // The leaf holds the RLP-encoded slot with leading zeros stripped
+    let leaf = resolve(proof.storage_hash, &storage_key, &storage_proof.proof)?;
+    let slot = rlp::Rlp::new(&leaf)
+        .data()
+        .map_err(|_| MptError::InvalidNode {
+            depth: storage_proof.proof.len().saturating_sub(1),
+        })?
+        .to_vec();
+    let storage_value: [u8; 32] = left_pad(&slot, MAX_STORAGE_VALUE_LENGTH)
+        .map_err(|e| PreludeError::Preprocess(format!("storage value: {}", e)))?
+        .try_into()
+        .map_err(|_| PreludeError::Preprocess("padded storage value".to_string()))?;
+    if let Some(expected) = query.value {
+        if expected.0 != storage_value {
+            return Err(MptError::ValueMismatch {
+                expected: expected.as_bytes().to_vec(),
+                actual: storage_value.to_vec(),
+            }
+            .into());
+        }
+    }
+
+    let storage_trie_proof = preprocess_proof(
+        &storage_proof.proof,
+        storage_key.to_vec(),
+        storage_value.to_vec(),
+        STORAGE_PROOF_MAX_DEPTH,
         MAX_TRIE_NODE_LENGTH,
-        MAX_ACCOUNT_STATE_LENGTH,
+        MAX_STORAGE_VALUE_LENGTH,
     )
-    .map_err(|_| anyhow!("Preprocess account proof"))?;
-
-    Ok((
-        latest.as_u64(),
-        Inputs {
-            safe_address: safe_address.into(),
-            msg_hash: msg_hash.into(),
-            header_rlp,
-            header_rlp_len,
-            state_root: block.state_root.into(),
-            storage_root: proof.storage_hash.into(),
-            storage_key,
-            account_proof_depth,
-            storage_proof_depth,
-            padded_account_value: padded_account_value
-                .try_into()
-                .map_err(|_| anyhow!("padded account value"))?,
-            account_proof: padded_account_proof
-                .try_into()
-                .map_err(|_| anyhow!("padded account proof"))?,
-            storage_proof: padded_storage_proof
-                .try_into()
-                .map_err(|_| anyhow!("padded storage proof"))?,
-        },
-    ))
-}
-
-// https://ethereum.stackexchange.com/a/67332
-// https://github.com/ethereum/go-ethereum/blob/14eb8967be7acc54c5dc9a416151ac45c01251b6/core/types/block.go#L65
-pub fn rlp_encode_header(block: &Block<H256>) -> (usize, [u8; HEADER_RLP_MAX_BYTES]) {
-    let mut rlp = RlpStream::new();
-    rlp.begin_list(20);
-    rlp.append(&block.parent_hash);
-    rlp.append(&block.uncles_hash);
-    rlp.append(&block.author.expect("author"));
-    rlp.append(&block.state_root);
-    rlp.append(&block.transactions_root);
-    rlp.append(&block.receipts_root);
-    rlp.append(&block.logs_bloom.expect("logs_bloom"));
-    rlp.append(&block.difficulty);
-    rlp.append(&block.number.expect("number"));
-    rlp.append(&block.gas_limit);
-    rlp.append(&block.gas_used);
-    rlp.append(&block.timestamp);
-    rlp.append(&block.extra_data.as_bytes().to_vec());
-    rlp.append(&block.mix_hash.expect("mix_hash"));
-    rlp.append(&block.nonce.expect("nonce"));
-    rlp.append(&block.base_fee_per_gas.expect("base_fee_per_gas")); // london
-    rlp.append(&block.withdrawals_root.expect("withdrawals_root")); // shanghai
-    rlp.append(&block.blob_gas_used.expect("blob_gas_used")); // cancun
-    rlp.append(&block.excess_blob_gas.expect("excess_blob_gas")); // cancun
-    rlp.append(
-        &block
-            .parent_beacon_block_root
-            .expect("parent_beacon_block_root"),
-    ); // cancun
-    let mut bytes: Vec<u8> = rlp.out().freeze().into();
-
-    let unpadded_length = bytes.len();
-    println!("unpadded_length {}", unpadded_length);
-    while bytes.len() < HEADER_RLP_MAX_BYTES {
-        bytes.push(0xff);
+    .map_err(|e| PreludeError::Preprocess(format!("storage proof: {}", e)))?;
+    storage_trie_proof.verify_storage_root(proof.storage_hash, STORAGE_PROOF_MAX_DEPTH)?;
+    let TrieProof {
+        proof: padded_storage_proof,
+        depth: storage_proof_depth,
+        ..
+    } = storage_trie_proof;
+
+    Ok(Inputs {
+        safe_address: proof.address.into(),
+        msg_hash: query.label().into(),
+        header_rlp: account.header_rlp,
+        header_rlp_len: account.header_rlp_len,
+        state_root: block.state_root.into(),
+        storage_root: proof.storage_hash.into(),
+        storage_key,
+        storage_value,
+        account_proof_depth: account.account_proof_depth,
+        storage_proof_depth,
+        padded_account_value: account.padded_account_value,
+        account_proof: account.account_proof,
+        storage_proof: padded_storage_proof
+            .try_into()
+            .map_err(|_| PreludeError::Preprocess("padded storage proof".to_string()))?,
+    })
+}
+
+/// Builds circuit inputs from a block and the EIP-1186 proof of the Safe's
+/// signedMessages entry at that block without touching the network.
+/// Returns the block number along with the inputs.
+///
+/// # Arguments
+/// * `block` - eth_getBlockBy*
+/// * `proof` - eth_getProof of `safe_address` at `block`
+/// * `safe_address` - Safe address
+/// * `msg_hash` - Message hash as signed by the Safe
+/// * `spec` - Chain parameters, i.e. the header layout and the mapping slot
+pub fn build_inputs(
+    block: &Block<H256>,
+    proof: &EIP1186ProofResponse,
+    safe_address: Address,
+    msg_hash: H256,
+    spec: &ChainSpec,
+) -> Result<(u64, Inputs)> {
+    let header_rlp = encode_anchor_header(block, spec)?;
+    build_inputs_with_header(block, header_rlp, proof, safe_address, msg_hash, spec)
+}
+
+/// Like `build_inputs` with the header as already encoded by
+/// `encode_anchor_header`, so that callers can time the two apart
+pub fn build_inputs_with_header(
+    block: &Block<H256>,
+    header_rlp: (usize, [u8; HEADER_RLP_MAX_BYTES]),
+    proof: &EIP1186ProofResponse,
+    safe_address: Address,
+    msg_hash: H256,
+    spec: &ChainSpec,
+) -> Result<(u64, Inputs)> {
+    if let Some(unsigned) = unsigned_message(block, proof, safe_address, msg_hash, spec)? {
+        return Err(unsigned.into());
+    }
+    let account = build_account_inputs(block, header_rlp, proof, safe_address)?;
+    let inputs = build_message_inputs(block, proof, &account, msg_hash, spec)?;
+    Ok((account.block_number, inputs))
+}
+
+/// Like `build_inputs` for an arbitrary storage slot of a contract
+///
+/// # Arguments
+/// * `block` - eth_getBlockBy*
+/// * `proof` - eth_getProof of `query.contract` at `block`
+/// * `query` - Slot and, optionally, the value it must hold
+/// * `spec` - Chain parameters, i.e. the header layout
+pub fn build_storage_inputs(
+    block: &Block<H256>,
+    proof: &EIP1186ProofResponse,
+    query: &StorageQuery,
+    spec: &ChainSpec,
+) -> Result<(u64, Inputs)> {
+    let header_rlp = encode_anchor_header(block, spec)?;
+    let account = build_account_inputs(block, header_rlp, proof, query.contract)?;
+    let inputs = build_slot_inputs(block, proof, &account, query)?;
+    Ok((account.block_number, inputs))
+}
+
+/// Like `build_inputs` for the owners entry of an address, which is non-zero,
+/// i.e. points to the next owner, iff the address is an owner of the Safe.
+/// Fails with `NotAnOwner` otherwise.
+///
+/// # Arguments
+/// * `block` - eth_getBlockBy*
+/// * `proof` - eth_getProof of the owners entry at `block`
+/// * `safe_address` - Safe address
+/// * `owner` - Presumed owner
+/// * `spec` - Chain parameters, i.e. the header layout and owners slot
+pub fn build_owner_inputs(
+    block: &Block<H256>,
+    proof: &EIP1186ProofResponse,
+    safe_address: Address,
+    owner: Address,
+    spec: &ChainSpec,
+) -> Result<(u64, Inputs)> {
+    let header_rlp = encode_anchor_header(block, spec)?;
+    let account = build_account_inputs(block, header_rlp, proof, safe_address)?;
+    let not_an_owner = || NotAnOwner {
+        safe: safe_address,
+        owner,
+        block: account.block_number,
+    };
+    if owner.is_zero() || owner.0 == SAFE_SENTINEL_OWNERS {
+        return Err(not_an_owner().into());
+    }
+    let query = StorageQuery::safe_owner(safe_address, owner, spec);
+    let inputs = build_slot_inputs(block, proof, &account, &query).map_err(|err| match err
+        .downcast_ref::<MptError>()
+    {
+        Some(MptError::Absent) => not_an_owner().into(),
+        _ => err,
+    })?;
+    Ok((account.block_number, inputs))
+}
+
+/// Like `build_storage_inputs` but proves a comparison of the storage value
+/// rather than the value itself. Fails with `ComparisonFailed` if the value
+/// does not satisfy it.
+///
+/// # Arguments
+/// * `block` - eth_getBlockBy*
+/// * `proof` - eth_getProof of `query.contract` at `block`
+/// * `query` - Slot, e.g. `StorageQuery::safe_setting`
+/// * `comparison` - Claim about the value, e.g. >= 2
+/// * `spec` - Chain parameters, i.e. the header layout
+pub fn build_comparison_inputs(
+    block: &Block<H256>,
+    proof: &EIP1186ProofResponse,
+    query: &StorageQuery,
+    comparison: &ValueComparison,
+    spec: &ChainSpec,
+) -> Result<(u64, Inputs)> {
+    let (block_number, inputs) = build_storage_inputs(block, proof, query, spec)?;
+    let value = U256::from_big_endian(&inputs.storage_value);
+    if !comparison.holds(value) {
+        return Err(ComparisonFailed {
+            contract: query.contract,
+            storage_key: H256(inputs.storage_key),
+            value,
+            comparison: *comparison,
+            block: block_number,
+        }
+        .into());
     }
+    Ok((block_number, inputs))
+}
 
-    (unpadded_length, bytes.try_into().expect("header_rlp"))
+/// Like `build_inputs` for several message hashes whose storage proofs are
+/// all part of `proof`; the header and account proof are encoded once and
+/// shared. Fails as a whole only if the shared parts are invalid and some
+/// message is signed.
+pub fn build_batch_inputs(
+    block: &Block<H256>,
+    proof: &EIP1186ProofResponse,
+    safe_address: Address,
+    msg_hashes: &[H256],
+    spec: &ChainSpec,
+) -> Result<(u64, Vec<Result<Inputs>>)> {
+    let unsigned = msg_hashes
+        .iter()
+        .map(|msg_hash| unsigned_message(block, proof, safe_address, *msg_hash, spec))
+        .collect::<Result<Vec<_>>>()?;
+    if !unsigned.is_empty() && unsigned.iter().all(Option::is_some) {
+        let block_number = block.number.ok_or(PreludeError::rpc("pending block"))?;
+        let inputs = unsigned
+            .into_iter()
+            .flatten()
+            .map(|unsigned| Err(unsigned.into()))
+            .collect();
+        return Ok((block_number.as_u64(), inputs));
+    }
+    let header_rlp = encode_anchor_header(block, spec)?;
+    let account = build_account_inputs(block, header_rlp, proof, safe_address)?;
+    let inputs = msg_hashes
+        .iter()
+        .map(|msg_hash| build_message_inputs(block, proof, &account, *msg_hash, spec))
+        .collect();
+    Ok((account.block_number, inputs))
 }
 
 pub fn lpad_bytes32(x: &[u8; 20]) -> [u8; 32] {
@@ -280,16 +971,6 @@ pub fn keccak256<T: AsRef<[u8]>>(input: T) -> [u8; 32] {
     out
 }
 
-pub fn trim_header(header_rlp: [u8; HEADER_RLP_MAX_BYTES]) -> Vec<u8> {
-    let mut out = header_rlp[0..HEADER_RLP_MIN_BYTES].to_vec();
-    for i in HEADER_RLP_MIN_BYTES..HEADER_RLP_MAX_BYTES {
-        if header_rlp[i] != 0xff {
-            out.push(header_rlp[i]);
-        }
-    }
-    out
-}
-
 /// Trie proof struct mirroring the equivalent Noir code
 pub struct TrieProof {
     /// Unhashed key
@@ -370,17 +1051,164 @@ pub fn preprocess_proof(
 /// Function for left padding a byte vector with zeros. Returns the padded vector.
 ///
 /// # Arguments
-/// * `v` - Byte vector
+/// * `v` - Bytes
 /// * `max_len` - Desired size of padded vector
-fn left_pad(v: &Vec<u8>, max_len: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+pub(crate) fn left_pad(v: &[u8], max_len: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     if v.len() > max_len {
-        Err(format!("The vector exceeds its maximum expected dimensions.").into())
+        Err("The vector exceeds its maximum expected dimensions.".into())
     } else {
-        let mut v_r = v.clone();
         let mut v_l = vec![0u8; max_len - v.len()];
 
-        v_l.append(&mut v_r);
+        v_l.extend_from_slice(v);
 
         Ok(v_l)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Snapshot of a Safe on Gnosis having signed a message at a Cancun block
+    const SNAPSHOT: &str = include_str!("fixtures/gnosis_35591564.json");
+
+    fn snapshot() -> Snapshot {
+        serde_json::from_str(SNAPSHOT).unwrap()
+    }
+
+    #[test]
+    fn replay_snapshot() {
+        let snapshot = snapshot();
+        let spec = ChainSpec {
+            layout: snapshot.layout,
+            ..ChainSpec::default()
+        };
+        let msg_hash = snapshot.msg_hashes[0];
+        let (block_number, inputs) = build_inputs(
+            &snapshot.block,
+            &snapshot.proof,
+            snapshot.safe_address,
+            msg_hash,
+            &spec,
+        )
+        .unwrap();
+        assert_eq!(block_number, 35591564);
+        assert_eq!(inputs.header_rlp_len, 590);
+        assert_eq!(
+            H256(keccak256(inputs.header())),
+            snapshot.block.hash.unwrap()
+        );
+        assert_eq!(inputs.storage_value, SAFE_SIGNED_MSG_VALUE);
+        let query = StorageQuery::safe_signed_message(snapshot.safe_address, msg_hash, &spec);
+        verify_inputs(&inputs, &query).unwrap();
+
+        // as proven by the circuits
+        let inputs_fe = InputsFe::from(inputs);
+        assert_eq!(
+            inputs_fe.blockhash,
+            "0x1bc337a28fc630fbfe649bda4df9f978887c6028044af64432868c0640adb5fc"
+        );
+        assert_eq!(
+            inputs_fe.challenge,
+            "0x110bf0d0ea1251f73ef2ce30e6aec8fb2423e7c5d6563104789100168e252811"
+        );
+        assert_eq!(
+            inputs_fe.input_hash,
+            "0x1a0b95e43dd5f661fa7bd9167bbf4a96b4233de0d90ca6a5705b7d0b1b45725b"
+        );
+    }
+
+    #[test]
+    fn replay_tampered_snapshot() {
+        let spec = ChainSpec::default();
+        let build = |snapshot: &Snapshot| {
+            build_inputs(
+                &snapshot.block,
+                &snapshot.proof,
+                snapshot.safe_address,
+                snapshot.msg_hashes[0],
+                &spec,
+            )
+            .unwrap_err()
+        };
+
+        let mut tampered = snapshot();
+        tampered.block.state_root = H256::zero();
+        assert!(matches!(
+            build(&tampered).downcast_ref::<PreflightError>(),
+            Some(PreflightError::HeaderHash { .. })
+        ));
+
+        let mut tampered = snapshot();
+        tampered.proof.account_proof[3].0 = vec![0xc0].into();
+        assert_eq!(
+            build(&tampered).downcast_ref::<MptError>(),
+            Some(&MptError::NodeHash { depth: 3 })
+        );
+
+        // an RPC reporting a signed message as unsigned is caught by its proof
+        let mut tampered = snapshot();
+        tampered.proof.storage_proof[0].value = U256::zero();
+        assert_eq!(
+            build(&tampered).downcast_ref::<MptError>(),
+            Some(&MptError::ValueMismatch {
+                expected: Vec::new(),
+                actual: vec![0x01],
+            })
+        );
+        tampered.proof.account_proof.clear();
+        assert_eq!(
+            build(&tampered).downcast_ref::<MptError>(),
+            Some(&MptError::MissingNode { depth: 0 })
+        );
+    }
+
+    #[test]
+    fn replay_unsigned_message() {
+        let mut snapshot = snapshot();
+        let spec = ChainSpec::default();
+        // A message whose storage key branches off at an empty child of the
+        // storage trie's root, proven absent by the root alone
+        let (msg_hash, storage_key) = (0u64..)
+            .map(H256::from_low_u64_be)
+            .map(|msg_hash| {
+                let query =
+                    StorageQuery::safe_signed_message(snapshot.safe_address, msg_hash, &spec);
+                (msg_hash, query.storage_key())
+            })
+            .find(|(_, storage_key)| keccak256(storage_key)[0] >> 4 == 3)
+            .unwrap();
+        snapshot.msg_hashes = vec![msg_hash];
+        let storage_proof = &mut snapshot.proof.storage_proof[0];
+        storage_proof.key = U256::from_big_endian(&storage_key);
+        storage_proof.value = U256::zero();
+        storage_proof.proof.truncate(1);
+
+        let err = build_inputs(
+            &snapshot.block,
+            &snapshot.proof,
+            snapshot.safe_address,
+            msg_hash,
+            &spec,
+        )
+        .unwrap_err();
+        let not_signed = err.downcast_ref::<MessageNotSigned>().unwrap();
+        assert_eq!(not_signed.block, 35591564);
+        assert_eq!(not_signed.msg_hash, msg_hash);
+
+        // but not under another state root
+        snapshot.block.state_root = H256::zero();
+        let err = build_inputs(
+            &snapshot.block,
+            &snapshot.proof,
+            snapshot.safe_address,
+            msg_hash,
+            &spec,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MptError>(),
+            Some(&MptError::NodeHash { depth: 0 })
+        );
+    }
+}
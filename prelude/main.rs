@@ -1,63 +1,929 @@
-use const_hex;
-use noir_safe_prelude::{fetch_inputs, InputsFe};
-use std::io::Write;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use ethers::types::{Address, U256};
+use noir_safe_prelude::{
+    build_batch_inputs, build_inputs, detect_safe_layout, fetch_comparison_inputs,
+    fetch_owner_inputs, fetch_snapshot, fetch_storage_inputs, parse_block_selector, pin_block,
+    verify_inputs, BlockId, ChainSpec, Comparison, ComparisonFailed, InputFormat, Inputs, InputsFe,
+    MappingKey, MessageNotSigned, NotAnOwner, SafeLayout, SafeSetting, Snapshot, StorageQuery,
+    ValueComparison, H256,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{create_dir_all, read_to_string, write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-#[tokio::main]
-async fn main() {
-    let rpc = std::env::var("RPC").unwrap_or("https://rpc.gnosis.gateway.fm".to_string());
-    let safe = const_hex::decode_to_array::<&str, 20>(
-        &std::env::var("SAFE").expect("must set env var SAFE=0x..."),
+/// Exit code signaling an unexpected failure, e.g. of the RPC
+const EXIT_FAILURE: u8 = 1;
+/// Exit code signaling that the Safe has not signed the message
+const EXIT_MESSAGE_NOT_SIGNED: u8 = 2;
+/// Exit code signaling that saved inputs failed verification
+const EXIT_INVALID_INPUTS: u8 = 3;
+/// Exit code signaling that the address is not an owner of the Safe
+const EXIT_NOT_AN_OWNER: u8 = 4;
+/// Exit code signaling that a storage value does not satisfy the comparison
+const EXIT_COMPARISON_FAILED: u8 = 5;
+/// Exit code signaling invalid command line arguments, see sysexits.h
+const EXIT_USAGE: u8 = 64;
+
+/// Fetches and preprocesses the inputs of the noir-safe circuits
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Fetches inputs from an RPC and writes them along with the prover files
+    Fetch(FetchArgs),
+    /// Like fetch but builds the inputs from a saved snapshot without an RPC
+    Replay(ReplayArgs),
+    /// Fetches the inputs of an address being an owner of a Safe; no circuit
+    /// proves them yet
+    FetchOwner(FetchOwnerArgs),
+    /// Fetches the inputs of a comparison of a Safe's threshold or owner
+    /// count; no circuit proves them yet
+    FetchSetting(FetchSettingArgs),
+    /// Fetches the inputs of an arbitrary storage slot of a contract; no
+    /// circuit proves them yet
+    FetchSlot(FetchSlotArgs),
+    /// Writes the prover files for previously fetched inputs
+    Encode(EncodeArgs),
+    /// Prints a summary of previously fetched inputs
+    Inspect(InspectArgs),
+    /// Checks previously fetched inputs offline
+    VerifyInputs(VerifyInputsArgs),
+}
+
+#[derive(Args)]
+struct FetchArgs {
+    #[arg(long, env = "RPC", default_value = "https://rpc.gnosis.gateway.fm")]
+    rpc: String,
+    /// Expected chain id; fails if the RPC serves another chain
+    #[arg(long)]
+    chain: Option<u64>,
+    /// Safe address
+    #[arg(long, env = "SAFE", value_parser = parse_hex::<20>)]
+    safe: [u8; 20],
+    /// Message hash as signed by the Safe; repeat or separate by commas to
+    /// fetch several messages of the Safe at once
+    #[arg(
+        long = "msg-hash",
+        env = "MSG_HASH",
+        required = true,
+        value_delimiter = ',',
+        value_parser = parse_hex::<32>
+    )]
+    msg_hashes: Vec<[u8; 32]>,
+    /// Block number, block hash or one of latest, safe, finalized
+    #[arg(long, env = "BLOCK", default_value = "latest", value_parser = parse_block)]
+    block: BlockId,
+    /// Also saves the fetched block and proof as JSON to replay them later
+    #[arg(long)]
+    save_snapshot: Option<PathBuf>,
+    #[command(flatten)]
+    layout: LayoutArgs,
+    #[command(flatten)]
+    out: OutArgs,
+    /// Format of the saved inputs
+    #[arg(long, value_enum, default_value_t = Format::Toml)]
+    format: Format,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// Snapshot as saved by fetch --save-snapshot
+    snapshot: PathBuf,
+    /// Expected chain id; fails if the snapshot is of another chain
+    #[arg(long)]
+    chain: Option<u64>,
+    #[command(flatten)]
+    out: OutArgs,
+    /// Format of the saved inputs
+    #[arg(long, value_enum, default_value_t = Format::Toml)]
+    format: Format,
+}
+
+#[derive(Args)]
+struct FetchOwnerArgs {
+    #[arg(long, env = "RPC", default_value = "https://rpc.gnosis.gateway.fm")]
+    rpc: String,
+    /// Safe address
+    #[arg(long, env = "SAFE", value_parser = parse_hex::<20>)]
+    safe: [u8; 20],
+    /// Presumed owner address
+    #[arg(long, value_parser = parse_hex::<20>)]
+    owner: [u8; 20],
+    #[command(flatten)]
+    layout: LayoutArgs,
+    /// Block number, block hash or one of latest, safe, finalized
+    #[arg(long, env = "BLOCK", default_value = "latest", value_parser = parse_block)]
+    block: BlockId,
+    #[command(flatten)]
+    out: OutArgs,
+    /// Format of the saved inputs
+    #[arg(long, value_enum, default_value_t = Format::Toml)]
+    format: Format,
+}
+
+#[derive(Args)]
+struct FetchSettingArgs {
+    #[arg(long, env = "RPC", default_value = "https://rpc.gnosis.gateway.fm")]
+    rpc: String,
+    /// Safe address
+    #[arg(long, env = "SAFE", value_parser = parse_hex::<20>)]
+    safe: [u8; 20],
+    #[arg(long, value_enum)]
+    setting: Setting,
+    #[command(flatten)]
+    comparison: ComparisonArgs,
+    #[command(flatten)]
+    layout: LayoutArgs,
+    /// Block number, block hash or one of latest, safe, finalized
+    #[arg(long, env = "BLOCK", default_value = "latest", value_parser = parse_block)]
+    block: BlockId,
+    #[command(flatten)]
+    out: OutArgs,
+    /// Format of the saved inputs
+    #[arg(long, value_enum, default_value_t = Format::Toml)]
+    format: Format,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Setting {
+    Threshold,
+    OwnerCount,
+}
+
+impl From<Setting> for SafeSetting {
+    fn from(setting: Setting) -> Self {
+        match setting {
+            Setting::Threshold => SafeSetting::Threshold,
+            Setting::OwnerCount => SafeSetting::OwnerCount,
+        }
+    }
+}
+
+#[derive(Args)]
+#[group(required = true, multiple = false)]
+struct ComparisonArgs {
+    /// Proves the value is at least this bound
+    #[arg(long)]
+    at_least: Option<u64>,
+    /// Proves the value is at most this bound
+    #[arg(long)]
+    at_most: Option<u64>,
+    /// Proves the value equals this bound
+    #[arg(long)]
+    equals: Option<u64>,
+}
+
+impl ComparisonArgs {
+    fn comparison(&self) -> ValueComparison {
+        let (op, bound) = match (self.at_least, self.at_most, self.equals) {
+            (Some(bound), _, _) => (Comparison::Gte, bound),
+            (_, Some(bound), _) => (Comparison::Lte, bound),
+            (_, _, Some(bound)) => (Comparison::Eq, bound),
+            // Enforced by the required arg group
+            _ => unreachable!("no comparison"),
+        };
+        ValueComparison {
+            op,
+            bound: bound.into(),
+        }
+    }
+}
+
+#[derive(Args)]
+struct FetchSlotArgs {
+    #[arg(long, env = "RPC", default_value = "https://rpc.gnosis.gateway.fm")]
+    rpc: String,
+    /// Contract address
+    #[arg(long, value_parser = parse_hex::<20>)]
+    contract: [u8; 20],
+    /// Slot of the variable, i.e. of the outermost mapping, as a decimal or
+    /// 0x-prefixed hex uint
+    #[arg(long, value_parser = parse_word)]
+    slot: H256,
+    /// Mapping key, from the outermost inwards: a decimal or 0x-prefixed hex
+    /// uint, which covers addresses, or bytes:0x.. or str:.. for dynamic keys
+    #[arg(long = "key", value_parser = parse_key)]
+    keys: Vec<MappingKey>,
+    /// Value the slot must hold; any non-zero value if unset
+    #[arg(long, value_parser = parse_word)]
+    value: Option<H256>,
+    /// Block number, block hash or one of latest, safe, finalized
+    #[arg(long, env = "BLOCK", default_value = "latest", value_parser = parse_block)]
+    block: BlockId,
+    #[command(flatten)]
+    out: OutArgs,
+    /// Format of the saved inputs
+    #[arg(long, value_enum, default_value_t = Format::Toml)]
+    format: Format,
+}
+
+#[derive(Args)]
+struct EncodeArgs {
+    /// Inputs as saved by fetch
+    inputs: PathBuf,
+    #[command(flatten)]
+    out: OutArgs,
+}
+
+#[derive(Args)]
+struct LayoutArgs {
+    /// Safe version whose storage layout to assume, e.g. 1.3.0; detected
+    /// from the Safe's VERSION() and singleton if unset
+    #[arg(long)]
+    safe_version: Option<String>,
+}
+
+impl LayoutArgs {
+    /// Chain parameters for the Safe's layout at a block pinned by
+    /// `pin_block`, so the layout is that of the block the inputs are of
+    async fn spec(&self, rpc: &str, safe: Address, block: BlockId) -> Result<ChainSpec> {
+        let layout = match &self.safe_version {
+            Some(version) => SafeLayout::for_version(version)?,
+            None => detect_safe_layout(rpc, safe, block).await?,
+        };
+        Ok(ChainSpec {
+            layout,
+            ..ChainSpec::default()
+        })
+    }
+}
+
+#[derive(Args, Clone)]
+struct OutArgs {
+    /// Project root whose circuits/ and target/ directories receive the files
+    #[arg(long, default_value = ".")]
+    out_dir: PathBuf,
+    /// Request id distinguishing the files of concurrent runs
+    #[arg(long, env = "REQ_ID")]
+    req_id: String,
+    /// Encoding of the message hash the circuits take: v1 as one reduced
+    /// field element or v2 as two 128-bit limbs
+    #[arg(long, env = "INPUT_FORMAT", default_value = "v1", value_parser = parse_input_format)]
+    input_format: InputFormat,
+}
+
+#[derive(Args)]
+struct InspectArgs {
+    /// Inputs as saved by fetch
+    inputs: PathBuf,
+    #[arg(long, value_enum, default_value_t = Format::Toml)]
+    format: Format,
+    /// Input format whose challenge to derive, see fetch
+    #[arg(long, env = "INPUT_FORMAT", default_value = "v1", value_parser = parse_input_format)]
+    input_format: InputFormat,
+}
+
+#[derive(Args)]
+struct VerifyInputsArgs {
+    /// Inputs as saved by fetch
+    inputs: PathBuf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Toml => "toml",
+            Format::Json => "json",
+        }
+    }
+
+    fn to_string<T: Serialize>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            Format::Toml => toml::to_string(value)?,
+            Format::Json => serde_json::to_string_pretty(value)?,
+        })
+    }
+}
+
+/// Inputs along with the number of the block they were fetched at
+#[derive(Serialize, Deserialize)]
+struct Fetched {
+    block_number: u64,
+    inputs: Inputs,
+    /// Slot the inputs prove; a Safe 1.x signedMessages entry if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query: Option<StorageQuery>,
+    /// Claim about the storage value proven in place of the value itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comparison: Option<ValueComparison>,
+}
+
+impl Fetched {
+    /// The slot the inputs were fetched for
+    fn query(&self, spec: &ChainSpec) -> StorageQuery {
+        self.query.clone().unwrap_or_else(|| {
+            StorageQuery::safe_signed_message(
+                self.inputs.safe_address.into(),
+                H256(self.inputs.msg_hash),
+                spec,
+            )
+        })
+    }
+
+    /// Fails unless the shipped circuits can prove the inputs, i.e. they are
+    /// of a Safe's signedMessages entry holding 1 without a comparison, since
+    /// the storage proof circuit fixes that value and the challenge would
+    /// otherwise misrepresent what the proof attests to, and are encoded in
+    /// the v1 input format the shipped anchor circuit derives its challenge of
+    fn check_provable(&self, input_format: InputFormat) -> Result<()> {
+        if input_format != InputFormat::V1 {
+            return Err(anyhow!(
+                "the shipped anchor circuit derives the v1 challenge only, so no circuit proves {} inputs yet",
+                input_format
+            )
+            .context(Unprovable));
+        }
+        if self.comparison.is_some() {
+            bail!("no circuit proves value comparisons yet");
+        }
+        if let Some(query) = &self.query {
+            let signed_message = StorageQuery {
+                slot: query.slot,
+                ..StorageQuery::safe_signed_message(
+                    self.inputs.safe_address.into(),
+                    H256(self.inputs.msg_hash),
+                    &ChainSpec::default(),
+                )
+            };
+            if *query != signed_message {
+                bail!("no circuit proves storage slots other than a Safe's signedMessages entries yet");
+            }
+        }
+        Ok(())
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let s = read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let fetched = match Format::of(path) {
+            Format::Toml => toml::from_str(&s)?,
+            Format::Json => serde_json::from_str(&s)?,
+        };
+        Ok(fetched)
+    }
+}
+
+/// Ties together the inputs of the messages of a batch
+#[derive(Serialize)]
+struct Manifest {
+    chain_id: u64,
+    safe_address: String,
+    block_number: u64,
+    block_hash: String,
+    messages: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    msg_hash: String,
+    /// Request id of the message's inputs and prover files
+    req_id: String,
+    /// One of ok, not_signed, failed
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Derived values of inputs to eyeball before proving
+#[derive(Serialize)]
+struct Summary {
+    block_number: u64,
+    blockhash: String,
+    challenge: String,
+    input_hash: String,
+    safe_address: String,
+    msg_hash: String,
+    state_root: String,
+    storage_root: String,
+    storage_key: String,
+    storage_value: String,
+    account_proof_depth: usize,
+    storage_proof_depth: usize,
+    header_rlp_len: usize,
+}
+
+fn parse_hex<const N: usize>(s: &str) -> Result<[u8; N], String> {
+    const_hex::decode_to_array::<&str, N>(s).map_err(|e| e.to_string())
+}
+
+fn parse_input_format(s: &str) -> Result<InputFormat, String> {
+    s.parse()
+}
+
+fn parse_block(s: &str) -> Result<BlockId, String> {
+    parse_block_selector(s).map_err(|e| e.to_string())
+}
+
+/// Parses a decimal or 0x-prefixed hex uint256 into a big-endian word
+fn parse_word(s: &str) -> Result<H256, String> {
+    let uint = match s.strip_prefix("0x") {
+        Some(digits) => U256::from_str_radix(digits, 16).map_err(|e| e.to_string())?,
+        None => U256::from_dec_str(s).map_err(|e| e.to_string())?,
+    };
+    let mut word = [0u8; 32];
+    uint.to_big_endian(&mut word);
+    Ok(H256(word))
+}
+
+fn parse_key(s: &str) -> Result<MappingKey, String> {
+    if let Some(hex) = s.strip_prefix("bytes:") {
+        return const_hex::decode(hex)
+            .map(MappingKey::Bytes)
+            .map_err(|e| e.to_string());
+    }
+    if let Some(string) = s.strip_prefix("str:") {
+        return Ok(MappingKey::Bytes(string.as_bytes().to_vec()));
+    }
+    parse_word(s).map(MappingKey::Word)
+}
+
+/// Writes the prover files of the shard circuits and the anchor block number
+/// to where the scripts and nargo expect them
+fn write_prover_files(out: &OutArgs, fetched: Fetched) -> Result<InputsFe> {
+    fetched.check_provable(out.input_format)?;
+    let inputs_fe = InputsFe::new(fetched.inputs, out.input_format);
+    let prover_toml = toml::to_string(&inputs_fe)?;
+    let an_prover_toml = format!("{}\nblocknumber = {}", prover_toml, fetched.block_number);
+    for (dir, tag, payload) in [
+        ("storage_proof", "sp", &prover_toml),
+        ("account_proof", "ap", &prover_toml),
+        ("anchor", "an", &an_prover_toml),
+    ] {
+        let path = out
+            .out_dir
+            .join("circuits")
+            .join(dir)
+            .join(format!("{}_prover_{}.toml", tag, out.req_id));
+        write(&path, payload).with_context(|| format!("write {}", path.display()))?;
+    }
+    write_anchor(out, fetched.block_number)?;
+    Ok(inputs_fe)
+}
+
+fn write_anchor(out: &OutArgs, block_number: u64) -> Result<()> {
+    let target = out.out_dir.join("target");
+    create_dir_all(&target)?;
+    let path = target.join(format!("anchor_{}.txt", out.req_id));
+    write(&path, block_number.to_string()).with_context(|| format!("write {}", path.display()))
+}
+
+async fn fetch(args: FetchArgs) -> Result<()> {
+    let block = pin_block(&args.rpc, args.block).await?;
+    let spec = args.layout.spec(&args.rpc, args.safe.into(), block).await?;
+    let msg_hashes = args
+        .msg_hashes
+        .iter()
+        .map(|msg_hash| H256(*msg_hash))
+        .collect::<Vec<_>>();
+    let snapshot = fetch_snapshot(&args.rpc, args.safe.into(), &msg_hashes, block, &spec).await?;
+    if let Some(path) = &args.save_snapshot {
+        write(path, serde_json::to_string_pretty(&snapshot)?)
+            .with_context(|| format!("write {}", path.display()))?;
+    }
+    build(&snapshot, args.chain, &spec, &args.out, args.format)
+}
+
+fn replay(args: ReplayArgs) -> Result<()> {
+    let s = read_to_string(&args.snapshot)
+        .with_context(|| format!("read {}", args.snapshot.display()))?;
+    let snapshot = serde_json::from_str::<Snapshot>(&s)?;
+    let spec = ChainSpec {
+        layout: snapshot.layout,
+        ..ChainSpec::default()
+    };
+    build(&snapshot, args.chain, &spec, &args.out, args.format)
+}
+
+/// Builds inputs from a snapshot and saves them along with the prover files.
+/// A snapshot of several messages yields one set of files per message, with
+/// the message's index appended to the request id, plus a manifest.
+fn build(
+    snapshot: &Snapshot,
+    chain: Option<u64>,
+    spec: &ChainSpec,
+    out: &OutArgs,
+    format: Format,
+) -> Result<()> {
+    if let Some(expected) = chain {
+        if snapshot.chain_id != expected {
+            bail!("chain {} instead of {}", snapshot.chain_id, expected);
+        }
+    }
+
+    if let [msg_hash] = snapshot.msg_hashes[..] {
+        let (block_number, inputs) = match build_inputs(
+            &snapshot.block,
+            &snapshot.proof,
+            snapshot.safe_address,
+            msg_hash,
+            spec,
+        ) {
+            Ok(res) => res,
+            Err(err) => {
+                if let Some(not_signed) = err.downcast_ref::<MessageNotSigned>() {
+                    write_anchor(out, not_signed.block)?;
+                }
+                return Err(err);
+            }
+        };
+        save(
+            out,
+            format,
+            Fetched {
+                block_number,
+                inputs,
+                query: Some(StorageQuery::safe_signed_message(
+                    snapshot.safe_address,
+                    msg_hash,
+                    spec,
+                )),
+                comparison: None,
+            },
+        )?;
+        return Ok(());
+    }
+
+    let (block_number, batch) = build_batch_inputs(
+        &snapshot.block,
+        &snapshot.proof,
+        snapshot.safe_address,
+        &snapshot.msg_hashes,
+        spec,
+    )?;
+    write_anchor(out, block_number)?;
+
+    let mut manifest = Manifest {
+        chain_id: snapshot.chain_id,
+        safe_address: format!("{:?}", snapshot.safe_address),
+        block_number,
+        block_hash: format!("{:?}", snapshot.block.hash.unwrap_or_default()),
+        messages: Vec::new(),
+    };
+    let mut failures = Vec::new();
+    for (i, (msg_hash, inputs)) in snapshot.msg_hashes.iter().zip(batch).enumerate() {
+        let message_out = OutArgs {
+            req_id: format!("{}_{}", out.req_id, i),
+            ..out.clone()
+        };
+        let saved = inputs.and_then(|inputs| {
+            save(
+                &message_out,
+                format,
+                Fetched {
+                    block_number,
+                    inputs,
+                    query: Some(StorageQuery::safe_signed_message(
+                        snapshot.safe_address,
+                        *msg_hash,
+                        spec,
+                    )),
+                    comparison: None,
+                },
+            )
+        });
+        let (status, challenge, error) = match saved {
+            Ok(inputs_fe) => ("ok", Some(inputs_fe.challenge), None),
+            Err(err) => {
+                let status = if err.is::<MessageNotSigned>() {
+                    "not_signed"
+                } else {
+                    "failed"
+                };
+                let error = format!("{:#}", err);
+                failures.push(err);
+                (status, None, Some(error))
+            }
+        };
+        manifest.messages.push(ManifestEntry {
+            msg_hash: format!("{:?}", msg_hash),
+            req_id: message_out.req_id,
+            status,
+            challenge,
+            error,
+        });
+    }
+
+    let path =
+        out.out_dir
+            .join("target")
+            .join(format!("manifest_{}.{}", out.req_id, format.extension()));
+    write(&path, format.to_string(&manifest)?)
+        .with_context(|| format!("write {}", path.display()))?;
+
+    // A generic failure takes precedence over an unsigned message
+    let count = failures.len();
+    match failures
+        .into_iter()
+        .min_by_key(|err| err.is::<MessageNotSigned>())
+    {
+        Some(err) => Err(err.context(format!(
+            "{} of {} messages failed, see {}",
+            count,
+            snapshot.msg_hashes.len(),
+            path.display()
+        ))),
+        None => Ok(()),
+    }
+}
+
+async fn fetch_owner(args: FetchOwnerArgs) -> Result<()> {
+    let (safe, owner) = (Address::from(args.safe), Address::from(args.owner));
+    let block = pin_block(&args.rpc, args.block).await?;
+    let spec = args.layout.spec(&args.rpc, safe, block).await?;
+    let (block_number, inputs) =
+        match fetch_owner_inputs(&args.rpc, safe, owner, block, &spec).await {
+            Ok(res) => res,
+            Err(err) => {
+                if let Some(not_an_owner) = err.downcast_ref::<NotAnOwner>() {
+                    write_anchor(&args.out, not_an_owner.block)?;
+                }
+                return Err(err);
+            }
+        };
+    save_inputs(
+        &args.out,
+        args.format,
+        &Fetched {
+            block_number,
+            inputs,
+            query: Some(StorageQuery::safe_owner(safe, owner, &spec)),
+            comparison: None,
+        },
     )
-    .expect("env var SAFE");
-    let msg_hash = const_hex::decode_to_array::<&str, 32>(
-        &std::env::var("MSG_HASH").expect("must set env var MSG_HASH=0x..."),
+}
+
+async fn fetch_setting(args: FetchSettingArgs) -> Result<()> {
+    let block = pin_block(&args.rpc, args.block).await?;
+    let spec = args.layout.spec(&args.rpc, args.safe.into(), block).await?;
+    let query = StorageQuery::safe_setting(args.safe.into(), args.setting.into(), &spec);
+    let comparison = args.comparison.comparison();
+    let (block_number, inputs) =
+        match fetch_comparison_inputs(&args.rpc, &query, &comparison, block, &spec).await {
+            Ok(res) => res,
+            Err(err) => {
+                if let Some(failed) = err.downcast_ref::<ComparisonFailed>() {
+                    write_anchor(&args.out, failed.block)?;
+                }
+                return Err(err);
+            }
+        };
+    save_inputs(
+        &args.out,
+        args.format,
+        &Fetched {
+            block_number,
+            inputs,
+            query: Some(query),
+            comparison: Some(comparison),
+        },
     )
-    .expect("env var MSG_HASH");
-
-    let (anchor, inputs) = fetch_inputs(&rpc, safe.into(), msg_hash.into())
-        .await
-        .expect("fetch_inputs failed");
-
-    let cargo_manifest_dir = env!("CARGO_MANIFEST_DIR");
-    let inputs_fe = InputsFe::from(inputs);
-    let prover_toml = toml::to_string(&inputs_fe).expect("prover toml");
-    let an_prover_payload = format!("{}\nblocknumber = {}", prover_toml, anchor);
-    let req_id = std::env::var("REQ_ID").expect("must set env var REQ_ID=1734..");
-
-    let mut sp_prover_file = std::fs::File::create(format!(
-        "{}/../circuits/storage_proof/sp_prover_{}.toml",
-        cargo_manifest_dir, &req_id
-    ))
-    .expect("sp_prover_file");
-    let mut ap_prover_file = std::fs::File::create(format!(
-        "{}/../circuits/account_proof/ap_prover_{}.toml",
-        cargo_manifest_dir, &req_id
-    ))
-    .expect("ap_prover_file");
-    let mut an_prover_file = std::fs::File::create(format!(
-        "{}/../circuits/anchor/an_prover_{}.toml",
-        cargo_manifest_dir, &req_id
-    ))
-    .expect("an_prover_file");
-    let mut anchor_file = std::fs::File::create(format!(
-        "{}/../target/anchor_{}.txt",
-        cargo_manifest_dir, req_id
-    ))
-    .expect("anchor_file");
-
-    sp_prover_file
-        .write_all(prover_toml.as_bytes())
-        .expect("sp_prover_file write");
-
-    ap_prover_file
-        .write_all(prover_toml.as_bytes())
-        .expect("ap_prover_file write");
-
-    an_prover_file
-        .write_all(an_prover_payload.as_bytes())
-        .expect("an_prover_file write");
-
-    anchor_file
-        .write_all(&anchor.to_string().into_bytes())
-        .expect("anchor_file write");
+}
+
+async fn fetch_slot(args: FetchSlotArgs) -> Result<()> {
+    let query = StorageQuery {
+        contract: Address::from(args.contract),
+        slot: args.slot,
+        keys: args.keys,
+        value: args.value,
+    };
+    let (block_number, inputs) =
+        fetch_storage_inputs(&args.rpc, &query, args.block, &ChainSpec::default()).await?;
+    save_inputs(
+        &args.out,
+        args.format,
+        &Fetched {
+            block_number,
+            inputs,
+            query: Some(query),
+            comparison: None,
+        },
+    )
+}
+
+/// Saves inputs along with their prover files
+fn save(out: &OutArgs, format: Format, fetched: Fetched) -> Result<InputsFe> {
+    save_inputs(out, format, &fetched)?;
+    write_prover_files(out, fetched)
+}
+
+/// Saves inputs without prover files, e.g. of slots no circuit proves yet
+fn save_inputs(out: &OutArgs, format: Format, fetched: &Fetched) -> Result<()> {
+    let target = out.out_dir.join("target");
+    create_dir_all(&target)?;
+    let path = target.join(format!("inputs_{}.{}", out.req_id, format.extension()));
+    write(&path, format.to_string(fetched)?).with_context(|| format!("write {}", path.display()))
+}
+
+fn inspect(args: InspectArgs) -> Result<()> {
+    let fetched = Fetched::load(&args.inputs)?;
+    let inputs_fe = InputsFe::new(fetched.inputs, args.input_format);
+    let hex = |bytes: &[u8]| format!("0x{}", const_hex::encode(bytes));
+    let summary = Summary {
+        block_number: fetched.block_number,
+        safe_address: hex(&inputs_fe.safe_address),
+        msg_hash: hex(&inputs_fe.msg_hash),
+        state_root: hex(&inputs_fe.state_root),
+        storage_root: hex(&inputs_fe.storage_root),
+        storage_key: hex(&inputs_fe.storage_key),
+        storage_value: hex(&inputs_fe.storage_value),
+        account_proof_depth: inputs_fe.account_proof_depth,
+        storage_proof_depth: inputs_fe.storage_proof_depth,
+        header_rlp_len: inputs_fe.header_rlp_len,
+        blockhash: inputs_fe.blockhash,
+        challenge: inputs_fe.challenge,
+        input_hash: inputs_fe.input_hash,
+    };
+    print!("{}", args.format.to_string(&summary)?);
+    Ok(())
+}
+
+async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Fetch(args) => fetch(args).await,
+        Command::Replay(args) => replay(args),
+        Command::FetchOwner(args) => fetch_owner(args).await,
+        Command::FetchSetting(args) => fetch_setting(args).await,
+        Command::FetchSlot(args) => fetch_slot(args).await,
+        Command::Encode(args) => {
+            write_prover_files(&args.out, Fetched::load(&args.inputs)?)?;
+            Ok(())
+        }
+        Command::Inspect(args) => inspect(args),
+        Command::VerifyInputs(args) => {
+            let fetched = Fetched::load(&args.inputs)?;
+            let query = fetched.query(&ChainSpec::default());
+            verify_inputs(&fetched.inputs, &query).map_err(|err| err.context(InvalidInputs))?;
+            match fetched.comparison {
+                Some(comparison) => {
+                    let value = U256::from_big_endian(&fetched.inputs.storage_value);
+                    if comparison.holds(value) {
+                        Ok(())
+                    } else {
+                        Err(anyhow!(ComparisonFailed {
+                            contract: query.contract,
+                            storage_key: H256(fetched.inputs.storage_key),
+                            value,
+                            comparison,
+                            block: fetched.block_number,
+                        })
+                        .context(InvalidInputs))
+                    }
+                }
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+/// Marks a verify-inputs failure so it maps to its own exit code
+#[derive(Debug)]
+struct InvalidInputs;
+
+impl fmt::Display for InvalidInputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid inputs")
+    }
+}
+
+/// Marks inputs in an input format no shipped circuit takes so it maps to
+/// the usage exit code
+#[derive(Debug)]
+struct Unprovable;
+
+impl fmt::Display for Unprovable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unprovable inputs")
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(err) if err.use_stderr() => {
+            let _ = err.print();
+            return ExitCode::from(EXIT_USAGE);
+        }
+        Err(err) => err.exit(),
+    };
+    match run(cli.command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            if err.is::<MessageNotSigned>() {
+                ExitCode::from(EXIT_MESSAGE_NOT_SIGNED)
+            } else if err.is::<NotAnOwner>() {
+                ExitCode::from(EXIT_NOT_AN_OWNER)
+            } else if err.is::<InvalidInputs>() {
+                ExitCode::from(EXIT_INVALID_INPUTS)
+            } else if err.is::<ComparisonFailed>() {
+                ExitCode::from(EXIT_COMPARISON_FAILED)
+            } else if err.is::<Unprovable>() {
+                ExitCode::from(EXIT_USAGE)
+            } else {
+                ExitCode::from(EXIT_FAILURE)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inputs of the Safe on Gnosis having signed a message
+    fn fetched() -> Fetched {
+        let snapshot =
+            serde_json::from_str::<Snapshot>(include_str!("fixtures/gnosis_35591564.json"))
+                .unwrap();
+        let msg_hash = snapshot.msg_hashes[0];
+        let (block_number, inputs) = build_inputs(
+            &snapshot.block,
+            &snapshot.proof,
+            snapshot.safe_address,
+            msg_hash,
+            &ChainSpec::default(),
+        )
+        .unwrap();
+        Fetched {
+            block_number,
+            inputs,
+            query: Some(StorageQuery::safe_signed_message(
+                snapshot.safe_address,
+                msg_hash,
+                &ChainSpec::default(),
+            )),
+            comparison: None,
+        }
+    }
+
+    #[test]
+    fn mapping_keys() {
+        assert_eq!(
+            parse_key("str:safe"),
+            Ok(MappingKey::Bytes(b"safe".to_vec()))
+        );
+        assert_eq!(parse_key("str:"), Ok(MappingKey::Bytes(Vec::new())));
+        assert_eq!(
+            parse_key("bytes:0xc0ffee"),
+            Ok(MappingKey::Bytes(vec![0xc0, 0xff, 0xee]))
+        );
+        assert!(parse_key("bytes:0xc0f").is_err());
+        assert_eq!(
+            parse_key("0x38Ba7f4278A1482FA0a7bC8B261a9A673336EDDc"),
+            Ok(MappingKey::from(
+                "0x38ba7f4278a1482fa0a7bc8b261a9a673336eddc"
+                    .parse::<Address>()
+                    .unwrap()
+            ))
+        );
+        assert_eq!(parse_key("7"), Ok(MappingKey::from(U256::from(7))));
+        assert!(parse_key("safe").is_err());
+    }
+
+    #[test]
+    fn v2_inputs_are_saved_without_prover_files() {
+        let fetched = fetched();
+        fetched.check_provable(InputFormat::V1).unwrap();
+        assert!(fetched
+            .check_provable(InputFormat::V2)
+            .unwrap_err()
+            .is::<Unprovable>());
+
+        let out = OutArgs {
+            out_dir: std::env::temp_dir().join(format!("noir_safe_v2_{}", std::process::id())),
+            req_id: "v2".to_string(),
+            input_format: InputFormat::V2,
+        };
+        let err = save(&out, Format::Toml, fetched).unwrap_err();
+        assert!(err.is::<Unprovable>());
+        assert!(out.out_dir.join("target").join("inputs_v2.toml").exists());
+        assert!(!out.out_dir.join("circuits").exists());
+        assert!(!out.out_dir.join("target").join("anchor_v2.txt").exists());
+        std::fs::remove_dir_all(&out.out_dir).unwrap();
+    }
 }
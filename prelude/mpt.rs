@@ -0,0 +1,443 @@
+use crate::{keccak256, left_pad, TrieProof, MAX_TRIE_NODE_LENGTH};
+use ethers::types::H256;
+use rlp::Rlp;
+use std::fmt;
+
+/// Root of the empty trie, i.e. keccak256(rlp(""))
+pub const EMPTY_TRIE_ROOT: [u8; 32] = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MptError {
+    /// The proof ends before the key is resolved
+    MissingNode { depth: usize },
+    /// A node does not hash to the reference held by its parent or the root
+    NodeHash { depth: usize },
+    /// A node is neither a branch, an extension nor a leaf
+    InvalidNode { depth: usize },
+    /// The proof holds nodes beyond the one resolving the key
+    TrailingNodes { depth: usize },
+    /// The proof is deeper than the circuit admits
+    Depth { depth: usize, max: usize },
+    /// The key is provably absent from the trie
+    Absent,
+    /// The key resolves to a different value
+    ValueMismatch { expected: Vec<u8>, actual: Vec<u8> },
+}
+
+impl fmt::Display for MptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MptError::MissingNode { depth } => write!(f, "proof ends at depth {}", depth),
+            MptError::NodeHash { depth } => write!(f, "node hash mismatch at depth {}", depth),
+            MptError::InvalidNode { depth } => write!(f, "invalid node at depth {}", depth),
+            MptError::TrailingNodes { depth } => {
+                write!(f, "unused proof nodes from depth {}", depth)
+            }
+            MptError::Depth { depth, max } => {
+                write!(f, "proof depth {} exceeds maximum {}", depth, max)
+            }
+            MptError::Absent => write!(f, "key is absent from the trie"),
+            MptError::ValueMismatch { expected, actual } => write!(
+                f,
+                "value mismatch: expected 0x{} got 0x{}",
+                const_hex::encode(expected),
+                const_hex::encode(actual)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MptError {}
+
+/// Reference to a child node: either its hash or, if its RLP is shorter
+/// than 32 bytes, the node itself inlined into the parent
+enum NodeRef<'a> {
+    Empty,
+    Hash(H256),
+    Inline(&'a [u8]),
+}
+
+fn node_ref(item: Rlp<'_>, depth: usize) -> Result<NodeRef<'_>, MptError> {
+    if item.is_list() {
+        return Ok(NodeRef::Inline(item.as_raw()));
+    }
+    match item.data().map_err(|_| MptError::InvalidNode { depth })? {
+        [] => Ok(NodeRef::Empty),
+        h if h.len() == 32 => Ok(NodeRef::Hash(H256::from_slice(h))),
+        _ => Err(MptError::InvalidNode { depth }),
+    }
+}
+
+fn to_nibbles(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().flat_map(|b| [b >> 4, b & 0x0f]).collect()
+}
+
+/// Decodes a hex-prefix encoded path into (is_leaf, nibbles)
+fn decode_path(encoded: &[u8], depth: usize) -> Result<(bool, Vec<u8>), MptError> {
+    let nibbles = to_nibbles(encoded);
+    let flag = *nibbles.first().ok_or(MptError::InvalidNode { depth })?;
+    if flag > 3 {
+        return Err(MptError::InvalidNode { depth });
+    }
+    let odd = flag & 1 == 1;
+    let skip = if odd { 1 } else { 2 };
+    if nibbles.len() < skip {
+        return Err(MptError::InvalidNode { depth });
+    }
+    Ok((flag & 2 == 2, nibbles[skip..].to_vec()))
+}
+
+/// Walks a Merkle-Patricia proof from `root` along `keccak256(key)`.
+/// Returns the raw leaf value or `MptError::Absent` if the proof shows the
+/// key is not in the trie.
+///
+/// # Arguments
+/// * `root` - State or storage root
+/// * `key` - Unhashed key, i.e. an address or a storage key
+/// * `nodes` - Proof nodes, root first
+pub fn resolve<N: AsRef<[u8]>>(root: H256, key: &[u8], nodes: &[N]) -> Result<Vec<u8>, MptError> {
+    if nodes.is_empty() && root.0 == EMPTY_TRIE_ROOT {
+        return Err(MptError::Absent);
+    }
+
+    let path = to_nibbles(&keccak256(key));
+    let mut offset = 0;
+    let mut depth = 0;
+    let mut next = NodeRef::Hash(root);
+
+    let value = loop {
+        let node = match next {
+            NodeRef::Empty => break None,
+            NodeRef::Hash(hash) => {
+                let node = nodes
+                    .get(depth)
+                    .ok_or(MptError::MissingNode { depth })?
+                    .as_ref();
+                if keccak256(node) != hash.0 {
+                    return Err(MptError::NodeHash { depth });
+                }
+                depth += 1;
+                node
+            }
+            // Inlined nodes are part of their parent and consume no proof node
+            NodeRef::Inline(node) => node,
+        };
+        // Errors refer to the last consumed proof node
+        let at = depth.saturating_sub(1);
+        let rlp = Rlp::new(node);
+        match rlp
+            .item_count()
+            .map_err(|_| MptError::InvalidNode { depth: at })?
+        {
+            17 => {
+                if offset == path.len() {
+                    let value = rlp
+                        .at(16)
+                        .and_then(|v| v.data().map(|d| d.to_vec()))
+                        .map_err(|_| MptError::InvalidNode { depth: at })?;
+                    break Some(value).filter(|v| !v.is_empty());
+                }
+                let child = rlp
+                    .at(path[offset] as usize)
+                    .map_err(|_| MptError::InvalidNode { depth: at })?;
+                next = node_ref(child, at)?;
+                offset += 1;
+            }
+            2 => {
+                let encoded = rlp
+                    .at(0)
+                    .and_then(|p| p.data().map(|d| d.to_vec()))
+                    .map_err(|_| MptError::InvalidNode { depth: at })?;
+                let (is_leaf, nibbles) = decode_path(&encoded, at)?;
+                let rest = &path[offset..];
+                if is_leaf {
+                    if rest != nibbles.as_slice() {
+                        break None;
+                    }
+                    let value = rlp.at(1).and_then(|v| v.data().map(|d| d.to_vec()));
+                    break Some(value.map_err(|_| MptError::InvalidNode { depth: at })?);
+                }
+                if !rest.starts_with(&nibbles) || nibbles.is_empty() {
+                    break None;
+                }
+                let child = rlp.at(1).map_err(|_| MptError::InvalidNode { depth: at })?;
+                next = node_ref(child, at)?;
+                offset += nibbles.len();
+            }
+            _ => return Err(MptError::InvalidNode { depth: at }),
+        }
+    };
+
+    if depth != nodes.len() {
+        return Err(MptError::TrailingNodes { depth });
+    }
+
+    value.ok_or(MptError::Absent)
+}
+
+impl TrieProof {
+    /// Unpadded proof nodes as laid out in the flat proof
+    fn nodes(&self) -> Result<Vec<&[u8]>, MptError> {
+        (0..self.depth)
+            .map(|depth| {
+                let padded = self
+                    .proof
+                    .get(depth * MAX_TRIE_NODE_LENGTH..(depth + 1) * MAX_TRIE_NODE_LENGTH)
+                    .ok_or(MptError::MissingNode { depth })?;
+                let info = Rlp::new(padded)
+                    .payload_info()
+                    .map_err(|_| MptError::InvalidNode { depth })?;
+                padded
+                    .get(..info.total())
+                    .ok_or(MptError::InvalidNode { depth })
+            })
+            .collect()
+    }
+
+    fn verify(&self, root: H256, max_depth: usize) -> Result<Vec<u8>, MptError> {
+        if self.depth > max_depth {
+            return Err(MptError::Depth {
+                depth: self.depth,
+                max: max_depth,
+            });
+        }
+        resolve(root, &self.key, &self.nodes()?)
+    }
+
+    /// Mirrors `noir_trie_proofs::TrieProof::verify_state_root`: checks that the
+    /// address in `key` resolves to the left-padded account RLP in `value`
+    pub fn verify_state_root(&self, state_root: H256, max_depth: usize) -> Result<(), MptError> {
+        let account = self.verify(state_root, max_depth)?;
+        let actual = left_pad(&account, self.value.len()).map_err(|_| MptError::ValueMismatch {
+            expected: self.value.clone(),
+            actual: account.clone(),
+        })?;
+        if actual != self.value {
+            return Err(MptError::ValueMismatch {
+                expected: self.value.clone(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Mirrors `noir_trie_proofs::TrieProof::verify_storage_root`: checks that the
+    /// storage key in `key` resolves to the left-padded 32-byte slot in `value`
+    pub fn verify_storage_root(
+        &self,
+        storage_root: H256,
+        max_depth: usize,
+    ) -> Result<(), MptError> {
+        let leaf = self.verify(storage_root, max_depth)?;
+        let slot = Rlp::new(&leaf)
+            .data()
+            .map_err(|_| MptError::InvalidNode {
+                depth: self.depth.saturating_sub(1),
+            })?
+            .to_vec();
+        let actual = left_pad(&slot, self.value.len()).map_err(|_| MptError::ValueMismatch {
+            expected: self.value.clone(),
+            actual: slot.clone(),
+        })?;
+        if actual != self.value {
+            return Err(MptError::ValueMismatch {
+                expected: self.value.clone(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{preprocess_proof, Snapshot, ACCOUNT_PROOF_MAX_DEPTH, STORAGE_PROOF_MAX_DEPTH};
+    use ethers::types::Bytes;
+
+    /// eth_getProof of a Safe's signedMessages entry holding 1
+    fn snapshot() -> Snapshot {
+        serde_json::from_str(include_str!("fixtures/gnosis_35591564.json")).unwrap()
+    }
+
+    /// First key whose hashed path satisfies `matches`
+    fn find_key(matches: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+        (0u64..)
+            .map(|i| H256::from_low_u64_be(i).as_bytes().to_vec())
+            .find(|key| matches(&to_nibbles(&keccak256(key))))
+            .unwrap()
+    }
+
+    /// Big-endian storage key of the signedMessages entry
+    fn storage_key(snapshot: &Snapshot) -> Vec<u8> {
+        let mut key = vec![0; 32];
+        snapshot.proof.storage_proof[0].key.to_big_endian(&mut key);
+        key
+    }
+
+    /// Account RLP held by the leaf of the account proof
+    fn account_value(account_proof: &[Bytes]) -> Vec<u8> {
+        Rlp::new(account_proof.last().unwrap())
+            .at(1)
+            .and_then(|v| v.data().map(|d| d.to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn account_inclusion() {
+        let snapshot = snapshot();
+        let proof = &snapshot.proof;
+        let key = snapshot.safe_address.as_bytes().to_vec();
+        let account = account_value(&proof.account_proof);
+        assert_eq!(
+            resolve(snapshot.block.state_root, &key, &proof.account_proof),
+            Ok(account.clone())
+        );
+        let trie_proof = preprocess_proof(
+            &proof.account_proof,
+            key,
+            account,
+            ACCOUNT_PROOF_MAX_DEPTH,
+            MAX_TRIE_NODE_LENGTH,
+            crate::MAX_ACCOUNT_STATE_LENGTH,
+        )
+        .unwrap();
+        assert_eq!(
+            trie_proof.verify_state_root(snapshot.block.state_root, ACCOUNT_PROOF_MAX_DEPTH),
+            Ok(())
+        );
+        assert_eq!(
+            trie_proof.verify_state_root(snapshot.block.state_root, 6),
+            Err(MptError::Depth { depth: 7, max: 6 })
+        );
+    }
+
+    #[test]
+    fn storage_inclusion() {
+        let snapshot = snapshot();
+        let storage_hash = snapshot.proof.storage_hash;
+        let storage_proof = &snapshot.proof.storage_proof[0];
+        let key = storage_key(&snapshot);
+        assert_eq!(
+            resolve(storage_hash, &key, &storage_proof.proof),
+            Ok(vec![0x01])
+        );
+        let trie_proof = |value: u8| {
+            preprocess_proof(
+                &storage_proof.proof,
+                key.clone(),
+                vec![value],
+                STORAGE_PROOF_MAX_DEPTH,
+                MAX_TRIE_NODE_LENGTH,
+                crate::MAX_STORAGE_VALUE_LENGTH,
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            trie_proof(1).verify_storage_root(storage_hash, STORAGE_PROOF_MAX_DEPTH),
+            Ok(())
+        );
+        let mut expected = vec![0; 32];
+        expected[31] = 2;
+        let mut actual = vec![0; 32];
+        actual[31] = 1;
+        assert_eq!(
+            trie_proof(2).verify_storage_root(storage_hash, STORAGE_PROOF_MAX_DEPTH),
+            Err(MptError::ValueMismatch { expected, actual })
+        );
+    }
+
+    #[test]
+    fn exclusion_by_empty_branch_child() {
+        let snapshot = snapshot();
+        let storage_hash = snapshot.proof.storage_hash;
+        let storage_proof = &snapshot.proof.storage_proof[0].proof;
+
+        // The root branch has no child at nibble 3
+        let key = find_key(|path| path[0] == 3);
+        assert_eq!(
+            resolve(storage_hash, &key, &storage_proof[..1]),
+            Err(MptError::Absent)
+        );
+
+        // Below the root, the key's branch only has children at 10 and 14
+        let path = to_nibbles(&keccak256(storage_key(&snapshot)));
+        let key = find_key(|p| p[0] == path[0] && ![10, 14].contains(&p[1]));
+        assert_eq!(
+            resolve(storage_hash, &key, &storage_proof[..2]),
+            Err(MptError::Absent)
+        );
+        // Proving absence needs no further nodes
+        assert_eq!(
+            resolve(storage_hash, &key, storage_proof),
+            Err(MptError::TrailingNodes { depth: 2 })
+        );
+    }
+
+    #[test]
+    fn exclusion_by_diverging_leaf() {
+        let snapshot = snapshot();
+        let storage_hash = snapshot.proof.storage_hash;
+        let storage_proof = &snapshot.proof.storage_proof[0];
+        let path = to_nibbles(&keccak256(storage_key(&snapshot)));
+        // Descends to the same leaf whose path then differs from the key's
+        let key = find_key(|p| p[..2] == path[..2] && p[2] != path[2]);
+        assert_eq!(
+            resolve(storage_hash, &key, &storage_proof.proof),
+            Err(MptError::Absent)
+        );
+    }
+
+    #[test]
+    fn empty_trie() {
+        let no_nodes: [&[u8]; 0] = [];
+        assert_eq!(
+            resolve(H256(EMPTY_TRIE_ROOT), &[0x01], &no_nodes),
+            Err(MptError::Absent)
+        );
+        assert_eq!(
+            resolve(H256::zero(), &[0x01], &no_nodes),
+            Err(MptError::MissingNode { depth: 0 })
+        );
+    }
+
+    #[test]
+    fn tampered_node() {
+        let snapshot = snapshot();
+        let storage_hash = snapshot.proof.storage_hash;
+        let storage_proof = &snapshot.proof.storage_proof[0];
+        for depth in 0..storage_proof.proof.len() {
+            let mut nodes: Vec<Vec<u8>> = storage_proof.proof.iter().map(|n| n.to_vec()).collect();
+            let last = nodes[depth].len() - 1;
+            nodes[depth][last] ^= 1;
+            assert_eq!(
+                resolve(storage_hash, &storage_key(&snapshot), &nodes),
+                Err(MptError::NodeHash { depth })
+            );
+        }
+
+        let mut account_proof = snapshot.proof.account_proof.clone();
+        account_proof.swap(2, 3);
+        assert_eq!(
+            resolve(
+                snapshot.block.state_root,
+                snapshot.safe_address.as_bytes(),
+                &account_proof
+            ),
+            Err(MptError::NodeHash { depth: 2 })
+        );
+        account_proof.swap(2, 3);
+        account_proof.truncate(5);
+        assert_eq!(
+            resolve(
+                snapshot.block.state_root,
+                snapshot.safe_address.as_bytes(),
+                &account_proof
+            ),
+            Err(MptError::MissingNode { depth: 5 })
+        );
+    }
+}
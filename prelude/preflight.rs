@@ -0,0 +1,226 @@
+use crate::{
+    keccak256, Inputs, StorageQuery, TrieProof, ACCOUNT_PROOF_MAX_DEPTH, HEADER_RLP_MAX_BYTES,
+    STORAGE_PROOF_MAX_DEPTH,
+};
+use anyhow::Result;
+use ethers::types::{Address, Bytes, H256};
+use std::fmt;
+
+/// A cheap consistency check on fetched inputs that failed before proving
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PreflightError {
+    /// keccak256 of the unpadded header differs from the RPC's block hash
+    HeaderHash { expected: H256, actual: H256 },
+    /// The root node of the account proof or the given state root differs
+    /// from the header's state_root
+    StateRoot { expected: H256, actual: H256 },
+    /// The padded header is not an RLP list of the given length
+    HeaderRlp,
+    /// The inputs prove the storage of another contract than queried
+    Contract { expected: Address, actual: Address },
+    /// The storage key is not the one derived from the queried slot and keys,
+    /// e.g. keccak256(msg_hash + signed_messages_slot)
+    StorageKey { expected: H256, actual: H256 },
+    /// The storage value differs from the queried one
+    StorageValue { expected: H256, actual: H256 },
+    /// The terminal account node does not hold an RLP-encoded account
+    AccountRlp,
+    /// The storage root of the decoded account differs from eth_getProof::storageHash
+    StorageHash { expected: H256, actual: H256 },
+}
+
+impl PreflightError {
+    /// Name of the failed check
+    pub fn check(&self) -> &'static str {
+        match self {
+            PreflightError::HeaderHash { .. } => "header_hash",
+            PreflightError::StateRoot { .. } => "state_root",
+            PreflightError::HeaderRlp => "header_rlp",
+            PreflightError::Contract { .. } => "contract",
+            PreflightError::StorageKey { .. } => "storage_key",
+            PreflightError::StorageValue { .. } => "storage_value",
+            PreflightError::AccountRlp => "account_rlp",
+            PreflightError::StorageHash { .. } => "storage_hash",
+        }
+    }
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreflightError::HeaderHash { expected, actual }
+            | PreflightError::StateRoot { expected, actual }
+            | PreflightError::StorageHash { expected, actual }
+            | PreflightError::StorageKey { expected, actual }
+            | PreflightError::StorageValue { expected, actual } => write!(
+                f,
+                "preflight check {} failed: expected {:?} got {:?}",
+                self.check(),
+                expected,
+                actual
+            ),
+            PreflightError::Contract { expected, actual } => write!(
+                f,
+                "preflight check {} failed: expected {:?} got {:?}",
+                self.check(),
+                expected,
+                actual
+            ),
+            PreflightError::AccountRlp => write!(
+                f,
+                "preflight check {} failed: terminal account node holds no account",
+                self.check()
+            ),
+            PreflightError::HeaderRlp => write!(
+                f,
+                "preflight check {} failed: header is no RLP list of the given length",
+                self.check()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+/// Checks that the header, account proof and storage hash all commit to the
+/// same block before handing them to the provers
+///
+/// # Arguments
+/// * `block_hash` - Block hash as reported by the RPC
+/// * `header_rlp` - RLP-encoded header without padding
+/// * `state_root` - State root of the header
+/// * `account_proof` - eth_getProof::accountProof
+/// * `account_value` - RLP-encoded account extracted from the terminal account node
+/// * `storage_hash` - eth_getProof::storageHash
+pub fn preflight(
+    block_hash: H256,
+    header_rlp: &[u8],
+    state_root: H256,
+    account_proof: &[Bytes],
+    account_value: &[u8],
+    storage_hash: H256,
+) -> Result<(), PreflightError> {
+    let actual = H256(keccak256(header_rlp));
+    if actual != block_hash {
+        return Err(PreflightError::HeaderHash {
+            expected: block_hash,
+            actual,
+        });
+    }
+
+    let actual = H256(keccak256(
+        account_proof.first().map(|n| n.as_ref()).unwrap_or(&[]),
+    ));
+    if actual != state_root {
+        return Err(PreflightError::StateRoot {
+            expected: state_root,
+            actual,
+        });
+    }
+
+    // Account RLP is [nonce, balance, storage_root, code_hash]
+    let account = rlp::Rlp::new(account_value);
+    if account.item_count() != Ok(4) {
+        return Err(PreflightError::AccountRlp);
+    }
+    let actual = account
+        .val_at::<H256>(2)
+        .map_err(|_| PreflightError::AccountRlp)?;
+    if actual != storage_hash {
+        return Err(PreflightError::StorageHash {
+            expected: storage_hash,
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// Checks already preprocessed inputs offline, i.e. without the RPC's block
+/// hash: the header, both trie proofs and the storage key must all commit to
+/// the queried slot holding the value, e.g. the Safe having signed the message
+///
+/// # Arguments
+/// * `inputs` - Preprocessed inputs, e.g. as saved by the prelude binary
+/// * `query` - Slot the inputs were fetched for
+pub fn verify_inputs(inputs: &Inputs, query: &StorageQuery) -> Result<()> {
+    let header = inputs.header();
+    let list = rlp::Rlp::new(header);
+    let encoded_len = list.payload_info().map(|info| info.total());
+    if inputs.header_rlp_len > HEADER_RLP_MAX_BYTES
+        || !list.is_list()
+        || encoded_len != Ok(inputs.header_rlp_len)
+    {
+        return Err(PreflightError::HeaderRlp.into());
+    }
+    // Header RLP is [parent_hash, ommers_hash, coinbase, state_root, ..]
+    let expected = list
+        .val_at::<H256>(3)
+        .map_err(|_| PreflightError::HeaderRlp)?;
+    let state_root = H256(inputs.state_root);
+    if state_root != expected {
+        return Err(PreflightError::StateRoot {
+            expected,
+            actual: state_root,
+        }
+        .into());
+    }
+
+    let actual = Address::from(inputs.safe_address);
+    if actual != query.contract {
+        return Err(PreflightError::Contract {
+            expected: query.contract,
+            actual,
+        }
+        .into());
+    }
+    let expected = H256(query.storage_key());
+    let actual = H256(inputs.storage_key);
+    if actual != expected {
+        return Err(PreflightError::StorageKey { expected, actual }.into());
+    }
+    let actual = H256(inputs.storage_value);
+    if let Some(expected) = query.value.filter(|expected| *expected != actual) {
+        return Err(PreflightError::StorageValue { expected, actual }.into());
+    }
+
+    TrieProof {
+        key: inputs.safe_address.to_vec(),
+        proof: inputs.account_proof.to_vec(),
+        depth: inputs.account_proof_depth,
+        value: inputs.padded_account_value.to_vec(),
+    }
+    .verify_state_root(state_root, ACCOUNT_PROOF_MAX_DEPTH)?;
+
+    let account_value = inputs
+        .padded_account_value
+        .iter()
+        .position(|b| *b != 0)
+        .map(|start| &inputs.padded_account_value[start..])
+        .unwrap_or_default();
+    let account = rlp::Rlp::new(account_value);
+    if account.item_count() != Ok(4) {
+        return Err(PreflightError::AccountRlp.into());
+    }
+    let actual = account
+        .val_at::<H256>(2)
+        .map_err(|_| PreflightError::AccountRlp)?;
+    let storage_root = H256(inputs.storage_root);
+    if actual != storage_root {
+        return Err(PreflightError::StorageHash {
+            expected: storage_root,
+            actual,
+        }
+        .into());
+    }
+
+    TrieProof {
+        key: inputs.storage_key.to_vec(),
+        proof: inputs.storage_proof.to_vec(),
+        depth: inputs.storage_proof_depth,
+        value: inputs.storage_value.to_vec(),
+    }
+    .verify_storage_root(storage_root, STORAGE_PROOF_MAX_DEPTH)?;
+
+    Ok(())
+}
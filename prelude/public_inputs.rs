@@ -0,0 +1,293 @@
+use crate::{blockhash_fe, challenge_fe, BindingMismatch, InputFormat, PreludeError};
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, PrimeField};
+use ethers::types::{H256, U256};
+use serde::{Deserialize, Serialize};
+
+/// Limbs of the pairing point accumulator the aggregation exposes
+pub const ACCUMULATOR_LIMBS: usize = 16;
+/// Byte length of the public inputs leading an aggregated proof
+pub const AGGREGATED_PUBLIC_INPUTS_BYTES: usize = 32 * (2 + ACCUMULATOR_LIMBS);
+
+/// Public inputs of the aggregation circuit in the order bb writes them ahead
+/// of the proof, i.e. the anchor's outputs followed by the accumulator
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregatedPublicInputs {
+    /// keccak256(header_rlp) reduced into the BN254 scalar field
+    pub blockhash: H256,
+    /// poseidon(safe_address, msg_hash)
+    pub challenge: H256,
+    /// Pairing point accumulator of the three recursive verifications
+    pub accumulator: [H256; ACCUMULATOR_LIMBS],
+}
+
+impl AggregatedPublicInputs {
+    /// Parses the public inputs leading an aggregated proof as written by
+    /// bb prove; the proof may follow
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PreludeError> {
+        let bytes = bytes.get(..AGGREGATED_PUBLIC_INPUTS_BYTES).ok_or_else(|| {
+            PreludeError::InvalidPublicInputs(format!(
+                "expected {} bytes got {}",
+                AGGREGATED_PUBLIC_INPUTS_BYTES,
+                bytes.len()
+            ))
+        })?;
+        Self::from_words(bytes.chunks(32).map(H256::from_slice).collect())
+    }
+
+    /// Parses the public inputs as 0x-prefixed hex of one field element each
+    pub fn from_hex<S: AsRef<str>>(public_inputs: &[S]) -> Result<Self, PreludeError> {
+        let words = public_inputs
+            .iter()
+            .map(|pi| {
+                let pi = pi.as_ref();
+                pi.strip_prefix("0x")
+                    .and_then(|digits| const_hex::decode_to_array::<&str, 32>(digits).ok())
+                    .map(H256)
+                    .ok_or_else(|| {
+                        PreludeError::InvalidPublicInputs(format!("{} is no 32-byte 0x hex", pi))
+                    })
+            })
+            .collect::<Result<Vec<H256>, PreludeError>>()?;
+        Self::from_words(words)
+    }
+
+    /// Expects blockhash, challenge and the accumulator limbs, all reduced
+    fn from_words(words: Vec<H256>) -> Result<Self, PreludeError> {
+        if words.len() != 2 + ACCUMULATOR_LIMBS {
+            return Err(PreludeError::InvalidPublicInputs(format!(
+                "expected blockhash, challenge and {} accumulator limbs, i.e. {} inputs, got {}",
+                ACCUMULATOR_LIMBS,
+                2 + ACCUMULATOR_LIMBS,
+                words.len()
+            )));
+        }
+        if let Some(word) = words.iter().find(|word| U256::from(word.0) >= modulus()) {
+            return Err(PreludeError::InvalidPublicInputs(format!(
+                "{:?} exceeds the BN254 scalar field",
+                word
+            )));
+        }
+        Ok(AggregatedPublicInputs {
+            blockhash: words[0],
+            challenge: words[1],
+            accumulator: core::array::from_fn(|i| words[2 + i]),
+        })
+    }
+
+    fn words(&self) -> impl Iterator<Item = &H256> {
+        [&self.blockhash, &self.challenge]
+            .into_iter()
+            .chain(self.accumulator.iter())
+    }
+
+    /// The public inputs as bb writes them ahead of the proof
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.words().flat_map(|word| word.0).collect()
+    }
+
+    /// The public inputs as 0x-prefixed hex of one field element each, the
+    /// way the Solidity verifier takes them
+    pub fn to_hex(&self) -> Vec<String> {
+        self.words()
+            .map(|word| format!("0x{}", const_hex::encode(word)))
+            .collect()
+    }
+
+    /// All 32-byte blockhashes that reduce to the blockhash field element;
+    /// as 2^256 < 6p there are at most 6 of them
+    pub fn blockhash_preimages(&self) -> Vec<H256> {
+        let p = modulus();
+        let mut preimages = vec![U256::from(self.blockhash.0)];
+        while let Some(next) = preimages[preimages.len() - 1].checked_add(p) {
+            preimages.push(next);
+        }
+        preimages
+            .into_iter()
+            .map(|preimage| {
+                let mut word = H256::zero();
+                preimage.to_big_endian(word.as_bytes_mut());
+                word
+            })
+            .collect()
+    }
+
+    /// Reconstructs the full blockhash from the field element, picking the
+    /// preimage that is keccak256 of the anchor's header
+    pub fn reconstruct_blockhash(&self, header_rlp: &[u8]) -> Option<H256> {
+        let hash = H256(crate::keccak256(header_rlp));
+        self.blockhash_preimages().contains(&hash).then_some(hash)
+    }
+
+    /// Recomputes the challenge of the Safe and message in the input format
+    /// the circuits were compiled for and the blockhash of the anchor header,
+    /// and checks that the proof commits to both; returns the anchor's full
+    /// blockhash
+    pub fn check_binding(
+        &self,
+        safe_address: &[u8; 20],
+        msg_hash: &[u8; 32],
+        input_format: InputFormat,
+        header_rlp: &[u8],
+    ) -> Result<H256, BindingMismatch> {
+        let expected = [
+            (
+                "blockhash",
+                fe_word(blockhash_fe(header_rlp)),
+                self.blockhash,
+            ),
+            (
+                "challenge",
+                fe_word(challenge_fe(safe_address, msg_hash, input_format)),
+                self.challenge,
+            ),
+        ];
+        for (input, expected, actual) in expected {
+            if expected != actual {
+                return Err(BindingMismatch {
+                    input,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok(H256(crate::keccak256(header_rlp)))
+    }
+}
+
+fn fe_word(fe: Fr) -> H256 {
+    H256::from_slice(&fe.into_bigint().to_bytes_be())
+}
+
+/// Modulus of the BN254 scalar field
+fn modulus() -> U256 {
+    U256::from_big_endian(&Fr::MODULUS.to_bytes_be())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAFE_ADDRESS: [u8; 20] = [0x5a; 20];
+    const MSG_HASH: [u8; 32] = [0xee; 32];
+
+    /// A header whose keccak256 exceeds the scalar field, i.e. reduces
+    fn large_header() -> Vec<u8> {
+        (0u8..)
+            .map(|i| vec![i])
+            .find(|header| U256::from(crate::keccak256(header)) >= modulus())
+            .unwrap()
+    }
+
+    fn public_inputs(header_rlp: &[u8], input_format: InputFormat) -> AggregatedPublicInputs {
+        AggregatedPublicInputs {
+            blockhash: fe_word(blockhash_fe(header_rlp)),
+            challenge: fe_word(challenge_fe(&SAFE_ADDRESS, &MSG_HASH, input_format)),
+            accumulator: core::array::from_fn(|i| H256::from_low_u64_be(i as u64)),
+        }
+    }
+
+    #[test]
+    fn bytes_roundtrip() {
+        let pi = public_inputs(&large_header(), InputFormat::V1);
+        let bytes = pi.to_bytes();
+        assert_eq!(bytes.len(), AGGREGATED_PUBLIC_INPUTS_BYTES);
+        assert_eq!(&bytes[..32], pi.blockhash.as_bytes());
+        assert_eq!(&bytes[32..64], pi.challenge.as_bytes());
+        assert_eq!(AggregatedPublicInputs::from_bytes(&bytes).unwrap(), pi);
+        // the proof may follow
+        let mut ag_proof = bytes.clone();
+        ag_proof.extend([0xab; 64]);
+        assert_eq!(AggregatedPublicInputs::from_bytes(&ag_proof).unwrap(), pi);
+        assert!(matches!(
+            AggregatedPublicInputs::from_bytes(&bytes[1..]),
+            Err(PreludeError::InvalidPublicInputs(_))
+        ));
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        let pi = public_inputs(&large_header(), InputFormat::V2);
+        let hex = pi.to_hex();
+        assert_eq!(hex.len(), 2 + ACCUMULATOR_LIMBS);
+        assert!(hex
+            .iter()
+            .all(|word| word.len() == 66 && word.starts_with("0x")));
+        assert_eq!(AggregatedPublicInputs::from_hex(&hex).unwrap(), pi);
+        for invalid in [
+            hex[..hex.len() - 1].to_vec(),
+            [hex.clone(), vec![hex[0].clone()]].concat(),
+            [vec![hex[0][2..].to_string()], hex[1..].to_vec()].concat(),
+            [vec![format!("{}00", hex[0])], hex[1..].to_vec()].concat(),
+            [vec![format!("0x{}", "ff".repeat(32))], hex[1..].to_vec()].concat(),
+        ] {
+            assert!(matches!(
+                AggregatedPublicInputs::from_hex(&invalid),
+                Err(PreludeError::InvalidPublicInputs(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn blockhash_preimages() {
+        let p = modulus();
+        let mut pi = public_inputs(&[], InputFormat::V1);
+        pi.blockhash = H256::from_low_u64_be(1);
+        let preimages = pi.blockhash_preimages();
+        assert_eq!(preimages.len(), 6);
+        for (k, preimage) in preimages.iter().enumerate() {
+            assert_eq!(U256::from(preimage.0), p * k + 1);
+        }
+        // p - 1 + 5p exceeds 2^256
+        pi.blockhash = H256::from(<[u8; 32]>::from(p - 1));
+        assert_eq!(pi.blockhash_preimages().len(), 5);
+    }
+
+    #[test]
+    fn reconstruct_reduced_blockhash() {
+        let header_rlp = large_header();
+        let hash = H256(crate::keccak256(&header_rlp));
+        let pi = public_inputs(&header_rlp, InputFormat::V1);
+        assert_ne!(pi.blockhash, hash);
+        assert_eq!(U256::from(hash.0) % modulus(), U256::from(pi.blockhash.0));
+        assert_eq!(pi.reconstruct_blockhash(&header_rlp), Some(hash));
+        assert_eq!(pi.reconstruct_blockhash(&[0xc0]), None);
+    }
+
+    #[test]
+    fn check_binding() {
+        let header_rlp = large_header();
+        let hash = H256(crate::keccak256(&header_rlp));
+        for input_format in [InputFormat::V1, InputFormat::V2] {
+            let pi = public_inputs(&header_rlp, input_format);
+            assert_eq!(
+                pi.check_binding(&SAFE_ADDRESS, &MSG_HASH, input_format, &header_rlp),
+                Ok(hash)
+            );
+        }
+        let pi = public_inputs(&header_rlp, InputFormat::V1);
+        let mismatch =
+            |safe_address: &[u8; 20], msg_hash: &[u8; 32], input_format, header_rlp: &[u8]| {
+                pi.check_binding(safe_address, msg_hash, input_format, header_rlp)
+                    .unwrap_err()
+            };
+        let other_header = [0xc0];
+        let err = mismatch(&SAFE_ADDRESS, &MSG_HASH, InputFormat::V1, &other_header);
+        assert_eq!(err.input, "blockhash");
+        assert_eq!(err.actual, pi.blockhash);
+        assert_eq!(err.expected, fe_word(blockhash_fe(&other_header)));
+        for (safe_address, msg_hash, input_format) in [
+            ([0x5b; 20], MSG_HASH, InputFormat::V1),
+            (SAFE_ADDRESS, [0xef; 32], InputFormat::V1),
+            (SAFE_ADDRESS, MSG_HASH, InputFormat::V2),
+        ] {
+            let err = mismatch(&safe_address, &msg_hash, input_format, &header_rlp);
+            assert_eq!(err.input, "challenge");
+            assert_eq!(err.actual, pi.challenge);
+            assert_eq!(
+                err.expected,
+                fe_word(challenge_fe(&safe_address, &msg_hash, input_format))
+            );
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{keccak256, ChainSpec, SAFE_SIGNED_MSG_VALUE};
+use ethers::types::{Address, H256, U256};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A key of a Solidity mapping
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MappingKey {
+    /// A value type key, e.g. an address or uint256, as a left-padded word
+    Word(H256),
+    /// A string or bytes key, hashed as is without padding
+    Bytes(Vec<u8>),
+}
+
+impl MappingKey {
+    /// The bytes the key contributes to the slot preimage
+    fn preimage(&self) -> Vec<u8> {
+        match self {
+            MappingKey::Word(word) => word.as_bytes().to_vec(),
+            MappingKey::Bytes(bytes) => bytes.clone(),
+        }
+    }
+}
+
+impl From<Address> for MappingKey {
+    fn from(address: Address) -> Self {
+        MappingKey::Word(address.into())
+    }
+}
+
+impl From<H256> for MappingKey {
+    fn from(word: H256) -> Self {
+        MappingKey::Word(word)
+    }
+}
+
+impl From<U256> for MappingKey {
+    fn from(uint: U256) -> Self {
+        let mut word = [0u8; 32];
+        uint.to_big_endian(&mut word);
+        MappingKey::Word(H256(word))
+    }
+}
+
+/// A plain storage variable of a Safe
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SafeSetting {
+    OwnerCount,
+    Threshold,
+}
+
+impl SafeSetting {
+    pub fn slot(&self, spec: &ChainSpec) -> H256 {
+        match self {
+            SafeSetting::OwnerCount => H256(spec.layout.owner_count_slot),
+            SafeSetting::Threshold => H256(spec.layout.threshold_slot),
+        }
+    }
+}
+
+/// Relation a storage value must bear to a bound
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Comparison {
+    Eq,
+    Gte,
+    Lte,
+}
+
+/// A claim about a storage value, e.g. threshold >= 2, to be proven in place
+/// of the value itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueComparison {
+    pub op: Comparison,
+    pub bound: U256,
+}
+
+impl ValueComparison {
+    pub fn holds(&self, value: U256) -> bool {
+        match self.op {
+            Comparison::Eq => value == self.bound,
+            Comparison::Gte => value >= self.bound,
+            Comparison::Lte => value <= self.bound,
+        }
+    }
+}
+
+impl fmt::Display for ValueComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            Comparison::Eq => "==",
+            Comparison::Gte => ">=",
+            Comparison::Lte => "<=",
+        };
+        write!(f, "{} {}", op, self.bound)
+    }
+}
+
+/// A storage slot of a contract whose value is to be proven, given either
+/// directly or as an entry of a possibly nested mapping
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageQuery {
+    pub contract: Address,
+    /// Slot of the variable, i.e. of the outermost mapping
+    pub slot: H256,
+    /// Mapping keys from the outermost inwards; empty for a plain variable
+    #[serde(default)]
+    pub keys: Vec<MappingKey>,
+    /// Value the slot must hold, if any; otherwise any non-zero value
+    #[serde(default)]
+    pub value: Option<H256>,
+}
+
+impl StorageQuery {
+    /// The Safe's signedMessages entry of a message hash, expected to be 1
+    pub fn safe_signed_message(safe: Address, msg_hash: H256, spec: &ChainSpec) -> Self {
+        StorageQuery {
+            contract: safe,
+            slot: H256(spec.layout.signed_messages_slot),
+            keys: vec![MappingKey::Word(msg_hash)],
+            value: Some(H256(SAFE_SIGNED_MSG_VALUE)),
+        }
+    }
+
+    /// The Safe's owners entry of an address, any non-zero value of which
+    /// links to the next owner
+    pub fn safe_owner(safe: Address, owner: Address, spec: &ChainSpec) -> Self {
+        StorageQuery {
+            contract: safe,
+            slot: H256(spec.layout.owners_slot),
+            keys: vec![MappingKey::from(owner)],
+            value: None,
+        }
+    }
+
+    /// A plain Safe variable such as its threshold
+    pub fn safe_setting(safe: Address, setting: SafeSetting, spec: &ChainSpec) -> Self {
+        StorageQuery {
+            contract: safe,
+            slot: setting.slot(spec),
+            keys: Vec::new(),
+            value: None,
+        }
+    }
+
+    /// Derives the storage key as solc lays out mappings, i.e.
+    /// keccak256(key . slot) applied once per mapping key
+    pub fn storage_key(&self) -> [u8; 32] {
+        self.keys.iter().fold(self.slot.0, |slot, key| {
+            let mut preimage = key.preimage();
+            preimage.extend_from_slice(&slot);
+            keccak256(preimage)
+        })
+    }
+
+    /// Word the challenge commits to: the innermost mapping key, hashed if
+    /// it is dynamic, or the slot itself for a plain variable
+    pub fn label(&self) -> H256 {
+        match self.keys.last() {
+            Some(MappingKey::Word(word)) => *word,
+            Some(MappingKey::Bytes(bytes)) => H256(keccak256(bytes)),
+            None => self.slot,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{encode, encode_packed, Token};
+
+    const OWNER: &str = "0x38ba7f4278a1482fa0a7bc8b261a9a673336eddc";
+    const SPENDER: &str = "0xe91d153e0b41518a2ce8dd3d7944fa863463a97d";
+
+    /// Slot of a value type key per the Solidity storage layout docs, i.e.
+    /// keccak256(abi.encode(key, slot))
+    fn value_slot(key: Token, slot: H256) -> H256 {
+        H256(keccak256(encode(&[
+            key,
+            Token::FixedBytes(slot.0.to_vec()),
+        ])))
+    }
+
+    /// Slot of a string or bytes key, i.e. keccak256(abi.encodePacked(key, slot))
+    fn dynamic_slot(key: Token, slot: H256) -> H256 {
+        H256(keccak256(
+            encode_packed(&[key, Token::FixedBytes(slot.0.to_vec())]).unwrap(),
+        ))
+    }
+
+    fn variable(slot: u64, keys: Vec<MappingKey>) -> StorageQuery {
+        StorageQuery {
+            contract: Address::zero(),
+            slot: H256::from_low_u64_be(slot),
+            keys,
+            value: None,
+        }
+    }
+
+    #[test]
+    fn plain_variable() {
+        let query = variable(4, Vec::new());
+        assert_eq!(query.storage_key(), H256::from_low_u64_be(4).0);
+        assert_eq!(query.label(), H256::from_low_u64_be(4));
+    }
+
+    #[test]
+    fn signed_message() {
+        // signedMessages entry of a Safe on Gnosis as proven by eth_getProof
+        let msg_hash: H256 = "0xa225aed0c0283cef82b24485b8b28fb756fc9ce83d25e5cf799d0c8aa20ce6b7"
+            .parse()
+            .unwrap();
+        let query = StorageQuery::safe_signed_message(
+            OWNER.parse().unwrap(),
+            msg_hash,
+            &ChainSpec::default(),
+        );
+        assert_eq!(
+            H256(query.storage_key()),
+            "0x8fc241b7eaf929f4c5b3f5bd01abbdc2cc61368ac3c2cca9a28d5d410d4049d5"
+                .parse()
+                .unwrap()
+        );
+        assert_eq!(query.label(), msg_hash);
+    }
+
+    #[test]
+    fn value_keys() {
+        // mapping(uint256 => ..) at slot 0, key 0: keccak256 of 64 zero bytes
+        let query = variable(0, vec![U256::zero().into()]);
+        assert_eq!(
+            H256(query.storage_key()),
+            "0xad3228b676f7d3cd4284a5443f17f1962b36e491b30a40b2405849e597ba5fb5"
+                .parse()
+                .unwrap()
+        );
+
+        // balanceOf[owner] and allowance[owner][spender] of an ERC-20 laying
+        // out its balances at slot 0 and allowances at slot 1
+        let (owner, spender): (Address, Address) =
+            (OWNER.parse().unwrap(), SPENDER.parse().unwrap());
+        let balance = variable(0, vec![owner.into()]);
+        assert_eq!(
+            H256(balance.storage_key()),
+            value_slot(Token::Address(owner), H256::zero())
+        );
+        assert_eq!(balance.label(), H256::from(owner));
+        let allowance = variable(1, vec![owner.into(), spender.into()]);
+        assert_eq!(
+            H256(allowance.storage_key()),
+            value_slot(
+                Token::Address(spender),
+                value_slot(Token::Address(owner), H256::from_low_u64_be(1))
+            )
+        );
+        assert_eq!(allowance.label(), H256::from(spender));
+    }
+
+    #[test]
+    fn dynamic_keys() {
+        // mapping(string => uint256) at slot 5
+        let name = variable(5, vec![MappingKey::Bytes(b"safe".to_vec())]);
+        assert_eq!(
+            H256(name.storage_key()),
+            dynamic_slot(Token::String("safe".to_string()), H256::from_low_u64_be(5))
+        );
+        assert_eq!(name.label(), H256(keccak256(b"safe")));
+
+        // mapping(bytes => mapping(address => uint256)) at slot 6, keyed by
+        // bytes longer than a word, which are not padded
+        let bytes = vec![0xc0; 40];
+        let owner: Address = OWNER.parse().unwrap();
+        let nested = variable(6, vec![MappingKey::Bytes(bytes.clone()), owner.into()]);
+        assert_eq!(
+            H256(nested.storage_key()),
+            value_slot(
+                Token::Address(owner),
+                dynamic_slot(Token::Bytes(bytes), H256::from_low_u64_be(6))
+            )
+        );
+        assert_eq!(nested.label(), H256::from(owner));
+
+        // mapping(address => mapping(string => uint256)) hashes the innermost
+        // string key into the label
+        let nested = variable(6, vec![owner.into(), MappingKey::Bytes(Vec::new())]);
+        assert_eq!(
+            H256(nested.storage_key()),
+            dynamic_slot(
+                Token::String(String::new()),
+                value_slot(Token::Address(owner), H256::from_low_u64_be(6))
+            )
+        );
+        assert_eq!(nested.label(), H256(keccak256([])));
+    }
+}
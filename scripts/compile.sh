@@ -39,6 +39,10 @@ echo "$ag_vk_hash" > $d/target/vk_hash
 
 cp $d/target/ag_vk $d/target/vk
 $b contract -o $d/UltraVerifier.sol
+# the server reports the hash of the deployed bytecode at GET /vk
+if command -v forge >/dev/null; then
+  forge build --no-cache --root $d --contracts $d/UltraVerifier.sol --out $d/target/forge
+fi
 
 echo "sp_vk_hash = \"$sp_vk_hash\"
 sp_vk = $sp_vk_as_fields
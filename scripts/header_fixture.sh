@@ -0,0 +1,20 @@
+#!/bin/bash
+
+# Saves a block as returned by eth_getBlockByNumber to
+# prelude/fixtures/headers/<name>_<block>.json, where the prelude's header
+# tests check its encoding against the hash the chain reports
+# Usage: header_fixture.sh <rpc> <name> <block number>
+
+set -ueExo pipefail
+
+d=$(git rev-parse --show-toplevel)
+rpc=$1
+name=$2
+block=$3
+
+mkdir -p $d/prelude/fixtures/headers
+curl -sSf -X POST -H 'content-type: application/json' \
+    --data "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"eth_getBlockByNumber\",\"params\":[\"$(printf '0x%x' $block)\",false]}" \
+    $rpc \
+    | jq '.result | .transactions = []' \
+    > $d/prelude/fixtures/headers/${name}_${block}.json
@@ -6,7 +6,7 @@ d=$(git rev-parse --show-toplevel)
 
 $d/scripts/compile.sh
 
-cargo run --manifest-path $d/prelude/Cargo.toml
+cargo run --manifest-path $d/prelude/Cargo.toml -- fetch --out-dir $d
 
 $d/scripts/aggregate.sh
 
@@ -0,0 +1,139 @@
+use crate::NoirSafeResult;
+use anyhow::Result;
+use noir_safe_prelude::InputFormat;
+use std::path::Path;
+
+/// chain_id ++ safe_address ++ message_hash
+const PREFIX_BYTES: usize = 8 + 20 + 32;
+
+/// Persistent store of finished proofs keyed by
+/// (chain_id, safe_address, message_hash, block_number)
+pub struct Cache {
+    db: sled::Db,
+    /// Proofs of the circuits the server currently proves with
+    tree: sled::Tree,
+}
+
+impl Cache {
+    /// Opens the proofs made under the aggregation verification key
+    /// `vk_hash` in `input_format`, so proofs of previously compiled circuits
+    /// or of challenges in another format are never served
+    pub fn open<P: AsRef<Path>>(path: P, vk_hash: &str, input_format: InputFormat) -> Result<Self> {
+        let db = sled::open(path)?;
+        let tree = db.open_tree(format!("proofs:{}:{}", vk_hash, input_format))?;
+        Ok(Cache { db, tree })
+    }
+
+    fn prefix(chain_id: u64, safe_address: [u8; 20], msg_hash: [u8; 32]) -> Vec<u8> {
+        let mut prefix = Vec::with_capacity(PREFIX_BYTES + 8);
+        prefix.extend_from_slice(&chain_id.to_be_bytes());
+        prefix.extend_from_slice(&safe_address);
+        prefix.extend_from_slice(&msg_hash);
+        prefix
+    }
+
+    // Big-endian block numbers make entries of one message sort by block
+    fn key(chain_id: u64, safe_address: [u8; 20], msg_hash: [u8; 32], block: u64) -> Vec<u8> {
+        let mut key = Self::prefix(chain_id, safe_address, msg_hash);
+        key.extend_from_slice(&block.to_be_bytes());
+        key
+    }
+
+    /// Cached proof at height `block`; with `block_hash` given only one of
+    /// that very block, since after a reorg the entry at a height may be of
+    /// another block
+    pub fn get(
+        &self,
+        chain_id: u64,
+        safe_address: [u8; 20],
+        msg_hash: [u8; 32],
+        block: u64,
+        block_hash: Option<[u8; 32]>,
+    ) -> Result<Option<NoirSafeResult>> {
+        let result: Option<NoirSafeResult> = self
+            .tree
+            .get(Self::key(chain_id, safe_address, msg_hash, block))?
+            .map(|v| bincode::deserialize(&v))
+            .transpose()?;
+        Ok(result.filter(|res| match block_hash {
+            Some(hash) => res.anchor_block_hash == format!("0x{}", const_hex::encode(hash)),
+            None => true,
+        }))
+    }
+
+    /// Most recent cached proof in `min_block..=max_block`
+    pub fn latest(
+        &self,
+        chain_id: u64,
+        safe_address: [u8; 20],
+        msg_hash: [u8; 32],
+        min_block: u64,
+        max_block: u64,
+    ) -> Result<Option<NoirSafeResult>> {
+        let from = Self::key(chain_id, safe_address, msg_hash, min_block);
+        let to = Self::key(chain_id, safe_address, msg_hash, max_block);
+        self.tree
+            .range(from..=to)
+            .next_back()
+            .transpose()?
+            .map(|(_, v)| Ok(bincode::deserialize(&v)?))
+            .transpose()
+    }
+
+    pub fn insert(
+        &self,
+        safe_address: [u8; 20],
+        msg_hash: [u8; 32],
+        result: &NoirSafeResult,
+    ) -> Result<()> {
+        let key = Self::key(result.chain_id, safe_address, msg_hash, result.block_number);
+        self.tree.insert(key, bincode::serialize(result)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    const SAFE: [u8; 20] = [0x38; 20];
+    const MSG: [u8; 32] = [0xa2; 32];
+
+    fn result(block_number: u64, anchor_block_hash: [u8; 32]) -> NoirSafeResult {
+        NoirSafeResult {
+            chain_id: 100,
+            safe_address: format!("0x{}", const_hex::encode(SAFE)),
+            message_hash: format!("0x{}", const_hex::encode(MSG)),
+            block_number,
+            block_hash: String::new(),
+            anchor_block_hash: format!("0x{}", const_hex::encode(anchor_block_hash)),
+            challenge: String::new(),
+            proof: String::new(),
+            public_inputs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn hash_selector_skips_reorged_block() {
+        let path = env::temp_dir().join(format!("noir_safe_cache_{}", std::process::id()));
+        let cache = Cache::open(&path, "vk", InputFormat::V1).unwrap();
+        cache.insert(SAFE, MSG, &result(10, [1; 32])).unwrap();
+
+        let hit = cache.get(100, SAFE, MSG, 10, Some([1; 32])).unwrap();
+        assert_eq!(hit.unwrap().block_number, 10);
+        assert!(cache.get(100, SAFE, MSG, 10, None).unwrap().is_some());
+        // Another block at the same height, as after a reorg
+        assert!(cache
+            .get(100, SAFE, MSG, 10, Some([2; 32]))
+            .unwrap()
+            .is_none());
+        assert!(cache.get(100, SAFE, MSG, 11, None).unwrap().is_none());
+
+        assert!(cache.latest(100, SAFE, MSG, 5, 12).unwrap().is_some());
+        assert!(cache.latest(100, SAFE, MSG, 11, 12).unwrap().is_none());
+        drop(cache);
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{bail, Context, Result};
+use noir_safe_prelude::{
+    fetch_block_number, BlockId, BlockNumber, ChainSpec, ForkSchedule, MessageNotSigned,
+    PreludeError, SafeLayout,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, env, fs::read_to_string, future::Future, path::Path};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChainConfig {
+    pub chain_id: u64,
+    pub name: String,
+    /// RPC URLs in order of preference
+    pub rpcs: Vec<String>,
+    /// Env var that, if set, holds an RPC URL tried before `rpcs`
+    #[serde(default)]
+    pub rpc_env: Option<String>,
+    /// Hardfork schedule of the header layout; empty to detect it per block
+    #[serde(default)]
+    pub forks: ForkSchedule,
+    /// Opt-in storage slot of the Safe's signedMessages mapping, overriding
+    /// the one of the layout detected by the Safe's version
+    #[serde(default)]
+    pub safe_signed_messages_slot: Option<u64>,
+    /// Number of blocks `latest` is held back by
+    #[serde(default)]
+    pub confirmations: u64,
+}
+
+impl ChainConfig {
+    pub fn rpcs(&self) -> Vec<String> {
+        self.rpc_env
+            .as_ref()
+            .and_then(|var| env::var(var).ok())
+            .into_iter()
+            .chain(self.rpcs.iter().cloned())
+            .collect()
+    }
+
+    /// Runs `f` against each RPC in turn until one succeeds; an unsigned
+    /// message or an unsupported Safe version is a definite answer and is
+    /// not retried
+    pub async fn with_failover<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut last_err = None;
+        for rpc in self.rpcs() {
+            match f(rpc.clone()).await {
+                Ok(res) => return Ok(res),
+                Err(err)
+                    if err.is::<MessageNotSigned>()
+                        || matches!(
+                            err.downcast_ref::<PreludeError>(),
+                            Some(PreludeError::UnknownSafeLayout(_))
+                        ) =>
+                {
+                    return Err(err)
+                }
+                Err(err) => {
+                    log::warn!("rpc {} of chain {} failed: {}", rpc, self.chain_id, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.context("no rpcs")?)
+    }
+
+    /// Holds `latest` back by the configured number of confirmations
+    pub async fn anchor(&self, block: BlockId) -> Result<BlockId> {
+        if block != BlockId::Number(BlockNumber::Latest) || self.confirmations == 0 {
+            return Ok(block);
+        }
+        let latest = self
+            .with_failover(|rpc| async move { fetch_block_number(&rpc, block).await })
+            .await?;
+        Ok(latest.saturating_sub(self.confirmations).into())
+    }
+
+    /// Chain parameters for a Safe of the given layout
+    pub fn spec(&self, layout: SafeLayout) -> ChainSpec {
+        let mut spec = ChainSpec {
+            forks: self.forks.clone(),
+            layout,
+        };
+        if let Some(slot) = self.safe_signed_messages_slot {
+            spec.layout.signed_messages_slot = [0u8; 32];
+            spec.layout.signed_messages_slot[24..].copy_from_slice(&slot.to_be_bytes());
+        }
+        spec
+    }
+}
+
+#[derive(Deserialize)]
+struct ChainsToml {
+    chains: Vec<ChainConfig>,
+}
+
+/// Chains the server can prove against, loaded from a TOML file
+pub struct Registry {
+    chains: HashMap<u64, ChainConfig>,
+}
+
+impl Registry {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let toml = read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let ChainsToml { chains } =
+            toml::from_str(&toml).with_context(|| format!("parse {}", path.display()))?;
+        let mut registry = HashMap::new();
+        for chain in chains {
+            if chain.rpcs().is_empty() {
+                bail!("chain {} has no rpcs", chain.chain_id);
+            }
+            log::info!("chain {} {}", chain.chain_id, chain.name);
+            if let Some(dup) = registry.insert(chain.chain_id, chain) {
+                bail!("chain {} configured twice", dup.chain_id);
+            }
+        }
+        Ok(Registry { chains: registry })
+    }
+
+    pub fn get(&self, chain_id: u64) -> Option<&ChainConfig> {
+        self.chains.get(&chain_id)
+    }
+}
@@ -0,0 +1,235 @@
+use crate::orchestrator::{StageError, StageFailure};
+use noir_safe_prelude::{
+    BindingMismatch, HeaderError, MessageNotSigned, MptError, PreflightError, PreludeError,
+};
+use rocket::{
+    http::Status,
+    serde::json::{json, Value},
+};
+use std::fmt;
+
+/// Everything a proof request can fail with, each variant mapping to a
+/// status code and a stable `code` in the JSON body
+#[derive(Debug)]
+pub enum ApiError {
+    /// A request param is malformed
+    InvalidParams {
+        field: &'static str,
+        reason: String,
+    },
+    /// The chain id is not in the registry
+    UnsupportedChain(u64),
+    /// The selected block does not exist
+    BlockNotFound(String),
+    /// The Safe has not signed the message as of the anchor block
+    MessageNotSigned(MessageNotSigned),
+    /// All RPCs failed or returned incomplete data
+    Rpc(String),
+    /// The fetched inputs are inconsistent with each other
+    Preflight(PreflightError),
+    /// The fetched data cannot be laid out as circuit inputs
+    Preprocessing(String),
+    /// The Safe's version has no known storage layout
+    UnsupportedSafeVersion(String),
+    /// nargo rejected the inputs of a circuit
+    Witness(StageError),
+    /// bb failed to prove or convert a proof
+    Proving(StageError),
+    /// Reading or writing prover files failed
+    Artifact(std::io::Error),
+    /// The proof does not commit to the requested Safe, message or anchor
+    Binding(BindingMismatch),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::InvalidParams { .. } | ApiError::UnsupportedChain(_) => Status::BadRequest,
+            ApiError::BlockNotFound(_) | ApiError::MessageNotSigned(_) => Status::NotFound,
+            ApiError::Rpc(_) | ApiError::Preflight(_) => Status::BadGateway,
+            ApiError::Preprocessing(_)
+            | ApiError::UnsupportedSafeVersion(_)
+            | ApiError::Witness(_) => Status::UnprocessableEntity,
+            ApiError::Proving(StageError {
+                failure: StageFailure::Timeout(_),
+                ..
+            }) => Status::GatewayTimeout,
+            ApiError::Proving(_)
+            | ApiError::Artifact(_)
+            | ApiError::Binding(_)
+            | ApiError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    /// Machine-readable error code
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidParams { .. } => "invalid_params",
+            ApiError::UnsupportedChain(_) => "unsupported_chain",
+            ApiError::BlockNotFound(_) => "block_not_found",
+            ApiError::MessageNotSigned(_) => "message_not_signed",
+            ApiError::Rpc(_) => "rpc_error",
+            ApiError::Preflight(_) => "preflight_failed",
+            ApiError::Preprocessing(_) => "preprocessing_failed",
+            ApiError::UnsupportedSafeVersion(_) => "unsupported_safe_version",
+            ApiError::Witness(_) => "witness_generation_failed",
+            ApiError::Proving(StageError {
+                failure: StageFailure::Timeout(_),
+                ..
+            }) => "proving_timeout",
+            ApiError::Proving(_) => "proving_failed",
+            ApiError::Artifact(_) => "artifact_io",
+            ApiError::Binding(_) => "binding_mismatch",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn body(&self) -> Value {
+        match self {
+            ApiError::InvalidParams { field, reason } => json!({
+                "error": format!("t(ツ)_/¯ invalid {}", field),
+                "code": self.code(),
+                "field": field,
+                "detail": reason,
+            }),
+            ApiError::UnsupportedChain(chain_id) => json!({
+                "error": "t(ツ)_/¯ invalid chain id",
+                "code": self.code(),
+                "chain_id": chain_id,
+            }),
+            ApiError::MessageNotSigned(not_signed) => json!({
+                "error": "t(ツ)_/¯ message not signed",
+                "code": self.code(),
+                "safe_address": format!("{:?}", not_signed.safe),
+                "message_hash": format!("{:?}", not_signed.msg_hash),
+                "block_number": not_signed.block,
+            }),
+            ApiError::Preflight(err) => json!({
+                "error": "t(ツ)_/¯ inconsistent chain data",
+                "code": self.code(),
+                "check": err.check(),
+                "detail": err.to_string(),
+            }),
+            ApiError::Witness(err) | ApiError::Proving(err) => json!({
+                "error": format!("t(ツ)_/¯ {}", self.summary()),
+                "code": self.code(),
+                "circuit": err.circuit,
+                "step": err.step,
+                "detail": err.to_string(),
+            }),
+            // Internals may leak paths so only the summary is exposed
+            ApiError::Artifact(_) | ApiError::Internal(_) => json!({
+                "error": format!("t(ツ)_/¯ {}", self.summary()),
+                "code": self.code(),
+            }),
+            _ => json!({
+                "error": format!("t(ツ)_/¯ {}", self.summary()),
+                "code": self.code(),
+                "detail": self.detail(),
+            }),
+        }
+    }
+
+    pub fn response(&self) -> (Status, Value) {
+        (self.status(), self.body())
+    }
+
+    fn summary(&self) -> &'static str {
+        match self {
+            ApiError::InvalidParams { .. } => "invalid request params",
+            ApiError::UnsupportedChain(_) => "invalid chain id",
+            ApiError::BlockNotFound(_) => "no such block",
+            ApiError::MessageNotSigned(_) => "message not signed",
+            ApiError::Rpc(_) => "rpc failed",
+            ApiError::Preflight(_) => "inconsistent chain data",
+            ApiError::Preprocessing(_) => "preprocessing failed",
+            ApiError::UnsupportedSafeVersion(_) => "unsupported safe version",
+            ApiError::Witness(_) => "witness generation failed",
+            ApiError::Proving(_) => "proving failed",
+            ApiError::Binding(_) => "proof does not match the request",
+            ApiError::Artifact(_) | ApiError::Internal(_) => "internal server error",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            ApiError::InvalidParams { field, reason } => format!("{}: {}", field, reason),
+            ApiError::BlockNotFound(detail)
+            | ApiError::Rpc(detail)
+            | ApiError::Preprocessing(detail)
+            | ApiError::UnsupportedSafeVersion(detail)
+            | ApiError::Internal(detail) => detail.clone(),
+            ApiError::UnsupportedChain(chain_id) => format!("chain {}", chain_id),
+            ApiError::MessageNotSigned(err) => err.to_string(),
+            ApiError::Preflight(err) => err.to_string(),
+            ApiError::Witness(err) | ApiError::Proving(err) => err.to_string(),
+            ApiError::Artifact(err) => err.to_string(),
+            ApiError::Binding(err) => err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.detail())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<anyhow::Error> for ApiError {
+    /// Classifies the typed errors of the prelude and the orchestrator;
+    /// anything else is internal
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<ApiError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<MessageNotSigned>() {
+            Ok(err) => return ApiError::MessageNotSigned(err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<BindingMismatch>() {
+            Ok(err) => return ApiError::Binding(err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<PreflightError>() {
+            Ok(err) => return ApiError::Preflight(err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<StageError>() {
+            Ok(err)
+                if err.step == "nargo execute" && matches!(err.failure, StageFailure::Exit(_)) =>
+            {
+                return ApiError::Witness(err)
+            }
+            Ok(err) => return ApiError::Proving(err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<std::io::Error>() {
+            Ok(err) => return ApiError::Artifact(err),
+            Err(err) => err,
+        };
+        if let Some(err) = err.downcast_ref::<PreludeError>() {
+            return match err {
+                PreludeError::InvalidBlockSelector(_) => ApiError::InvalidParams {
+                    field: "block",
+                    reason: err.to_string(),
+                },
+                PreludeError::BlockNotFound(_) => ApiError::BlockNotFound(err.to_string()),
+                PreludeError::Rpc(_) => ApiError::Rpc(err.to_string()),
+                PreludeError::Preprocess(_) => ApiError::Preprocessing(err.to_string()),
+                PreludeError::UnknownSafeLayout(_) => {
+                    ApiError::UnsupportedSafeVersion(err.to_string())
+                }
+                // bb wrote public inputs the aggregation circuit cannot have
+                PreludeError::InvalidPublicInputs(_) => ApiError::Internal(err.to_string()),
+            };
+        }
+        if err.is::<HeaderError>() || err.is::<MptError>() {
+            return ApiError::Preprocessing(err.to_string());
+        }
+        ApiError::Internal(format!("{:#}", err))
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{get_epoch_millis, REQ_ID_ALPHABET};
+use nanoid::nanoid;
+use rocket::{
+    http::Status,
+    serde::json::{json, Value},
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Semaphore;
+
+/// Finished jobs are kept around for polling this long
+const JOB_TTL_MILLIS: u128 = 60 * 60 * 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobStatus {
+    Queued,
+    Fetching,
+    ProvingShards,
+    Aggregating,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Done | JobStatus::Failed)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Value>,
+    /// Status code the error of a failed job maps to
+    #[serde(skip)]
+    error_status: Option<Status>,
+    pub created_at: u128,
+    pub updated_at: u128,
+    /// Identifies equivalent requests so retries join the running job
+    #[serde(skip)]
+    key: String,
+}
+
+impl Job {
+    /// The job as polled: failed jobs answer with the status code their
+    /// error maps to, all others with 200
+    pub fn response(&self) -> (Status, Value) {
+        let status = match self.status {
+            JobStatus::Failed => self.error_status.unwrap_or(Status::InternalServerError),
+            _ => Status::Ok,
+        };
+        (status, json!(self))
+    }
+}
+
+type JobMap = Arc<Mutex<HashMap<String, Job>>>;
+
+/// Handle through which a running job reports its progress
+pub struct Progress {
+    id: String,
+    jobs: JobMap,
+}
+
+impl Progress {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn set(&self, status: JobStatus) {
+        update(&self.jobs, &self.id, |job| job.status = status);
+    }
+}
+
+fn update(jobs: &JobMap, id: &str, f: impl FnOnce(&mut Job)) {
+    if let Some(job) = jobs.lock().expect("jobs lock").get_mut(id) {
+        f(job);
+        job.updated_at = get_epoch_millis();
+    }
+}
+
+/// Proof jobs run in the background on a bounded pool of workers
+#[derive(Clone)]
+pub struct Jobs {
+    jobs: JobMap,
+    workers: Arc<Semaphore>,
+}
+
+impl Jobs {
+    pub fn new(workers: usize) -> Self {
+        Jobs {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            workers: Arc::new(Semaphore::new(workers)),
+        }
+    }
+
+    /// Queues `work` unless an unfinished job with the same key exists, in
+    /// which case that job is returned instead
+    pub fn submit<F, Fut>(&self, key: String, work: F) -> Job
+    where
+        F: FnOnce(Progress) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Value, (Status, Value)>> + Send + 'static,
+    {
+        let now = get_epoch_millis();
+        let mut jobs = self.jobs.lock().expect("jobs lock");
+        jobs.retain(|_, job| !job.status.is_finished() || now - job.updated_at < JOB_TTL_MILLIS);
+        if let Some(job) = jobs
+            .values()
+            .find(|job| job.key == key && !job.status.is_finished())
+        {
+            return job.clone();
+        }
+
+        let job = Job {
+            id: nanoid!(32, &REQ_ID_ALPHABET),
+            status: JobStatus::Queued,
+            result: None,
+            error: None,
+            error_status: None,
+            created_at: now,
+            updated_at: now,
+            key,
+        };
+        jobs.insert(job.id.clone(), job.clone());
+
+        let progress = Progress {
+            id: job.id.clone(),
+            jobs: self.jobs.clone(),
+        };
+        let workers = self.workers.clone();
+        tokio::spawn(async move {
+            let _permit = workers.acquire_owned().await.expect("workers closed");
+            let jobs = progress.jobs.clone();
+            let id = progress.id.clone();
+            // A panicking job must not stay unfinished forever
+            let outcome = tokio::spawn(work(progress)).await.unwrap_or_else(|_| {
+                Err((
+                    Status::InternalServerError,
+                    json!({ "error": "t(ツ)_/¯ proof job crashed", "code": "internal" }),
+                ))
+            });
+            update(&jobs, &id, |job| match outcome {
+                Ok(result) => {
+                    job.status = JobStatus::Done;
+                    job.result = Some(result);
+                }
+                Err((status, error)) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(error);
+                    job.error_status = Some(status);
+                }
+            });
+        });
+
+        job
+    }
+
+    /// Records a job that is done right away, e.g. because it was answered from the cache
+    pub fn finished(&self, result: Value) -> Job {
+        let now = get_epoch_millis();
+        let job = Job {
+            id: nanoid!(32, &REQ_ID_ALPHABET),
+            status: JobStatus::Done,
+            result: Some(result),
+            error: None,
+            error_status: None,
+            created_at: now,
+            updated_at: now,
+            key: String::new(),
+        };
+        self.jobs
+            .lock()
+            .expect("jobs lock")
+            .insert(job.id.clone(), job.clone());
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().expect("jobs lock").get(id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn finished(jobs: &Jobs, id: &str) -> Job {
+        loop {
+            let job = jobs.get(id).unwrap();
+            if job.status.is_finished() {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[rocket::async_test]
+    async fn failed_job_answers_with_mapped_status() {
+        let jobs = Jobs::new(1);
+        let job = jobs.submit("failed".to_string(), |_| async {
+            Err((
+                Status::NotFound,
+                json!({ "error": "not signed", "code": "message_not_signed" }),
+            ))
+        });
+        assert_eq!(job.response().0, Status::Ok);
+        let (status, body) = finished(&jobs, &job.id).await.response();
+        assert_eq!(status, Status::NotFound);
+        assert_eq!(body["status"], "failed");
+        assert_eq!(body["error"]["code"], "message_not_signed");
+
+        let job = jobs.submit("done".to_string(), |_| async {
+            Ok(json!({ "proof": "0x" }))
+        });
+        let (status, body) = finished(&jobs, &job.id).await.response();
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["status"], "done");
+    }
+
+    #[rocket::async_test]
+    async fn crashed_job_answers_with_internal_error() {
+        let jobs = Jobs::new(1);
+        let job = jobs.submit("crashed".to_string(), |_| async { panic!("crash") });
+        let (status, body) = finished(&jobs, &job.id).await.response();
+        assert_eq!(status, Status::InternalServerError);
+        assert_eq!(body["error"]["code"], "internal");
+    }
+}
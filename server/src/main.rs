@@ -1,27 +1,48 @@
 #[macro_use]
 extern crate rocket;
 
-use anyhow::{bail, Result};
+mod cache;
+mod chains;
+mod error;
+mod jobs;
+mod orchestrator;
+mod params;
+mod telemetry;
+mod vk;
+
+use anyhow::Result;
+use cache::Cache;
+use chains::{ChainConfig, Registry};
+use error::ApiError;
+use jobs::{JobStatus, Jobs, Progress};
 use nanoid::nanoid;
+use noir_safe_prelude::{
+    fetch_block_number, parse_block_selector, AggregatedPublicInputs, BlockId, PreludeError,
+    AGGREGATED_PUBLIC_INPUTS_BYTES,
+};
+use orchestrator::{Intermediates, Orchestrator, Proved, Stage, INPUT_FORMAT};
+use params::{parse_0x_bytes, parse_0x_hex, parse_address, to_checksum};
 use rocket::{
-    data::{Limits, ToByteUnit},
+    data::{Data, Limits, ToByteUnit},
     fairing::{Fairing, Info, Kind},
-    http::{Header, Method, Status},
+    http::{ContentType, Header, Method, Status},
     request::Request,
-    serde::json::{json, Json, Value},
-    Config, Response,
+    serde::json::{from_str, json, Json, Value},
+    Config, Response, State,
 };
 use serde::{Deserialize, Serialize};
 use std::{
     env,
-    fs::{read, read_to_string},
     net::Ipv4Addr,
-    process::Command,
+    path::PathBuf,
+    sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
+use telemetry::{init_logger, Metrics};
+use vk::VkInfo;
 
-const PUBLIC_INPUTS_BYTES: usize = 512 + 64;
-const REQ_ID_ALPHABET: [char; 16] = [
+const VERIFY_LIMIT_KIB: u64 = 32;
+pub const REQ_ID_ALPHABET: [char; 16] = [
     '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f'
 ];
 
@@ -30,6 +51,16 @@ pub struct NoirSafeParams {
     pub chain_id: u64,
     pub safe_address: String,
     pub message_hash: String,
+    /// Block number, 0x-prefixed block hash or one of latest, safe, finalized
+    #[serde(default)]
+    pub block: Option<String>,
+    /// Reuse a cached proof at most this many blocks older than the selected block
+    #[serde(default)]
+    pub max_age_blocks: Option<u64>,
+    /// Also return the shard proofs, verification key hashes and aggregation
+    /// inputs; such requests always prove afresh
+    #[serde(default)]
+    pub include_intermediates: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -38,17 +69,46 @@ pub struct NoirSafeResult {
     pub safe_address: String,
     pub message_hash: String,
     pub block_number: u64,
+    /// Blockhash of the anchor reduced into the scalar field, as the
+    /// aggregated proof commits to it
     pub block_hash: String,
+    /// Full blockhash of the anchor
+    pub anchor_block_hash: String,
     pub challenge: String,
     pub proof: String,
     pub public_inputs: Vec<String>,
 }
 
-fn is_0x_hex(len: usize, s: &str) -> bool {
-    if &s[0..2] != "0x" || (s.len() - 2) / 2 != len {
-        return false;
+/// A result as returned to clients, i.e. with its public inputs decoded and,
+/// if requested, the intermediates it was derived from
+#[derive(Clone, Debug, Serialize)]
+pub struct NoirSafeResponse {
+    #[serde(flatten)]
+    pub result: NoirSafeResult,
+    pub aggregated_public_inputs: AggregatedPublicInputs,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intermediates: Option<Intermediates>,
+}
+
+impl NoirSafeResponse {
+    pub fn new(
+        result: NoirSafeResult,
+        intermediates: Option<Intermediates>,
+    ) -> Result<Self, PreludeError> {
+        Ok(NoirSafeResponse {
+            aggregated_public_inputs: AggregatedPublicInputs::from_hex(&result.public_inputs)?,
+            result,
+            intermediates,
+        })
     }
-    true
+}
+
+/// An aggregated proof as returned by `POST /proof`; any other fields of the
+/// result are ignored
+#[derive(Clone, Debug, Deserialize)]
+pub struct VerifyParams {
+    pub proof: String,
+    pub public_inputs: Vec<String>,
 }
 
 pub fn get_epoch_millis() -> u128 {
@@ -58,86 +118,253 @@ pub fn get_epoch_millis() -> u128 {
         .as_millis()
 }
 
-async fn _proof(params: Json<NoirSafeParams>) -> Result<Value> {
-    log::info!("🏈 incoming request");
-    let dir = env::var("CARGO_MANIFEST_DIR").expect("cargo manifest dir");
-    let rpc = match params.chain_id {
-        100 => env::var("GNOSIS_RPC").unwrap_or("https://rpc.gnosis.gateway.fm".to_string()),
-        11155111 => env::var("SEPOLIA_RPC")
-            .unwrap_or("https://ethereum-sepolia-rpc.publicnode.com".to_string()),
-        _ => bail!("invalid chain_id {}", params.chain_id),
+/// Validated proof request
+struct ProofRequest {
+    params: NoirSafeParams,
+    chain: ChainConfig,
+    safe_address: [u8; 20],
+    msg_hash: [u8; 32],
+    block: BlockId,
+}
+
+fn validate(chains: &Registry, params: NoirSafeParams) -> Result<ProofRequest, ApiError> {
+    let chain = match chains.get(params.chain_id) {
+        Some(chain) => chain.clone(),
+        None => return Err(ApiError::UnsupportedChain(params.chain_id)),
     };
 
-    if !is_0x_hex(20, &params.safe_address) {
-        bail!("invalid safe address {}", &params.safe_address);
+    let safe_address = parse_address("safe_address", &params.safe_address)?;
+    let msg_hash = parse_0x_hex::<32>("message_hash", &params.message_hash)?;
+    let block = parse_block_selector(params.block.as_deref().unwrap_or("latest"))
+        .map_err(ApiError::from)?;
+
+    Ok(ProofRequest {
+        params,
+        chain,
+        safe_address,
+        msg_hash,
+        block,
+    })
+}
+
+async fn _proof(
+    orchestrator: &Orchestrator,
+    req: ProofRequest,
+    progress: &Progress,
+) -> Result<NoirSafeResponse> {
+    let Proved {
+        block_number: anchor,
+        mut ag_proof,
+        header_rlp,
+        input_format,
+        intermediates,
+    } = orchestrator
+        .prove(
+            progress.id(),
+            &req.chain,
+            req.safe_address,
+            req.msg_hash,
+            req.block,
+            &|stage| {
+                progress.set(match stage {
+                    Stage::Fetching => JobStatus::Fetching,
+                    Stage::ProvingShards => JobStatus::ProvingShards,
+                    Stage::Aggregating => JobStatus::Aggregating,
+                })
+            },
+        )
+        .await?;
+    let proofbin = ag_proof.split_off(AGGREGATED_PUBLIC_INPUTS_BYTES);
+    let public_inputs = AggregatedPublicInputs::from_bytes(&ag_proof)?;
+    // refuse proofs of anything but the requested Safe and message as of the
+    // anchor; the circuit outputs the blockhash reduced into the scalar field
+    let anchor_block_hash =
+        public_inputs.check_binding(&req.safe_address, &req.msg_hash, input_format, &header_rlp)?;
+
+    let result = NoirSafeResult {
+        chain_id: req.params.chain_id,
+        safe_address: to_checksum(&req.safe_address),
+        message_hash: format!("0x{}", const_hex::encode(req.msg_hash)),
+        block_number: anchor,
+        block_hash: format!("0x{}", const_hex::encode(public_inputs.blockhash)),
+        anchor_block_hash: format!("0x{}", const_hex::encode(anchor_block_hash)),
+        challenge: format!("0x{}", const_hex::encode(public_inputs.challenge)),
+        proof: format!("0x{}", const_hex::encode(proofbin)),
+        public_inputs: public_inputs.to_hex(),
+    };
+    Ok(NoirSafeResponse {
+        result,
+        aggregated_public_inputs: public_inputs,
+        intermediates: Some(intermediates),
+    })
+}
+
+/// Reassembles the aggregated proof as bb verify takes it, i.e. the public
+/// inputs followed by the proof, checking the public input layout
+fn ag_proof(params: &VerifyParams) -> Result<Vec<u8>, ApiError> {
+    let public_inputs = AggregatedPublicInputs::from_hex(&params.public_inputs).map_err(|err| {
+        ApiError::InvalidParams {
+            field: "public_inputs",
+            reason: err.to_string(),
+        }
+    })?;
+    let mut ag_proof = public_inputs.to_bytes();
+    ag_proof.extend(parse_0x_bytes("proof", &params.proof)?);
+    Ok(ag_proof)
+}
+
+/// Looks up a cached proof for the block the request currently selects
+async fn cached(cache: &Cache, req: &ProofRequest) -> Result<Option<NoirSafeResult>> {
+    let block_number = req
+        .chain
+        .with_failover(|rpc| async move { fetch_block_number(&rpc, req.block).await })
+        .await?;
+    match (req.block, req.params.max_age_blocks) {
+        // Heights may have been reorged, so a block selected by hash is only
+        // answered with a proof of that very block
+        (BlockId::Hash(hash), _) => cache.get(
+            req.params.chain_id,
+            req.safe_address,
+            req.msg_hash,
+            block_number,
+            Some(hash.0),
+        ),
+        (_, Some(max_age)) => cache.latest(
+            req.params.chain_id,
+            req.safe_address,
+            req.msg_hash,
+            block_number.saturating_sub(max_age),
+            block_number,
+        ),
+        (_, None) => cache.get(
+            req.params.chain_id,
+            req.safe_address,
+            req.msg_hash,
+            block_number,
+            None,
+        ),
     }
-    if !is_0x_hex(32, &params.message_hash) {
-        bail!("invalid msg hash {}", &params.message_hash);
+}
+
+fn error_response(metrics: &Metrics, err: ApiError) -> (Status, Value) {
+    log::error!("{}", err);
+    metrics.failure(err.code());
+    err.response()
+}
+
+#[post("/proof", data = "<params>")]
+async fn proof(
+    orchestrator: &State<Arc<Orchestrator>>,
+    jobs: &State<Jobs>,
+    cache: &State<Arc<Cache>>,
+    chains: &State<Registry>,
+    metrics: &State<Arc<Metrics>>,
+    params: Json<NoirSafeParams>,
+) -> (Status, Value) {
+    log::info!("🏈 incoming request");
+    let mut req = match validate(chains, params.into_inner()) {
+        Ok(req) => req,
+        Err(err) => return error_response(metrics, err),
+    };
+    req.block = match req.chain.anchor(req.block).await {
+        Ok(block) => block,
+        Err(err) => return error_response(metrics, err.into()),
+    };
+    // the cache only holds results, not the intermediates they came from
+    let include_intermediates = req.params.include_intermediates;
+    if !include_intermediates {
+        match cached(cache, &req).await {
+            Ok(Some(res)) => match NoirSafeResponse::new(res, None) {
+                Ok(res) => return (Status::Ok, json!(jobs.finished(json!(res)))),
+                Err(err) => log::warn!("cached result is corrupt: {}", err),
+            },
+            Ok(None) => {}
+            Err(err) => log::warn!("cache lookup failed: {}", err),
+        }
     }
-    let cargo = format!(
-        "{}/bin/cargo",
-        home::cargo_home().expect("cargo home").to_string_lossy()
+    let key = format!(
+        "{}:{}:{}:{:?}:{}:{}",
+        req.params.chain_id,
+        const_hex::encode(req.safe_address),
+        const_hex::encode(req.msg_hash),
+        req.block,
+        INPUT_FORMAT,
+        include_intermediates
     );
-    let req_id = nanoid!(32, &REQ_ID_ALPHABET);
-    let prelude = Command::new(cargo)
-        .arg("run")
-        .env("RPC", rpc)
-        .env("SAFE", &params.safe_address)
-        .env("MSG_HASH", &params.message_hash)
-        .env("REQ_ID", &req_id)
-        .arg("--manifest-path")
-        .arg(format!("{}/../prelude/Cargo.toml", dir))
-        .output()?;
-    if !prelude.status.success() {
-        log::error!("{}", String::from_utf8_lossy(&prelude.stderr));
-        bail!("prelude failed");
-    }
-    let anchor = {
-        let digits = read_to_string(format!("{}/../target/anchor_{}.txt", dir, req_id))?;
-        u64::from_str_radix(&digits, 10)?
+    let orchestrator = orchestrator.inner().clone();
+    let cache = cache.inner().clone();
+    let metrics = metrics.inner().clone();
+    let job = jobs.submit(key, move |progress| async move {
+        let (safe_address, msg_hash) = (req.safe_address, req.msg_hash);
+        match _proof(&orchestrator, req, &progress).await {
+            Ok(mut res) => {
+                if let Err(err) = cache.insert(safe_address, msg_hash, &res.result) {
+                    log::warn!("cache insert failed: {}", err);
+                }
+                if !include_intermediates {
+                    res.intermediates = None;
+                }
+                Ok(json!(res))
+            }
+            Err(err) => Err(error_response(&metrics, err.into())),
+        }
+    });
+
+    (
+        Status::Accepted,
+        json!({ "id": job.id, "status": job.status }),
+    )
+}
+
+#[post("/verify", data = "<data>")]
+async fn verify(
+    orchestrator: &State<Arc<Orchestrator>>,
+    metrics: &State<Arc<Metrics>>,
+    limits: &Limits,
+    data: Data<'_>,
+) -> (Status, Value) {
+    // proofs exceed the json limit meant for proof requests
+    let limit = limits.get("verify").unwrap_or(VERIFY_LIMIT_KIB.kibibytes());
+    let invalid = |reason: String| ApiError::InvalidParams {
+        field: "body",
+        reason,
+    };
+    let body = match data.open(limit).into_string().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return error_response(metrics, invalid(format!("exceeds {}", limit))),
+        Err(err) => return error_response(metrics, invalid(err.to_string())),
     };
-    let aggregation = Command::new(format!("{}/../scripts/aggregate.sh", dir))
-        .env("REQ_ID", &req_id)
-        .output()?;
-    if !aggregation.status.success() {
-        log::error!("{}", String::from_utf8_lossy(&aggregation.stderr));
-        bail!("aggregation failed");
+    let ag_proof = match from_str::<VerifyParams>(&body)
+        .map_err(|err| invalid(err.to_string()))
+        .and_then(|params| ag_proof(&params))
+    {
+        Ok(ag_proof) => ag_proof,
+        Err(err) => return error_response(metrics, err),
+    };
+    let req_id = nanoid!(32, &REQ_ID_ALPHABET);
+    match orchestrator.verify(&req_id, &ag_proof).await {
+        Ok(valid) => (
+            Status::Ok,
+            json!({
+                "valid": valid,
+                "block_hash": format!("0x{}", const_hex::encode(&ag_proof[0..32])),
+                "challenge": format!("0x{}", const_hex::encode(&ag_proof[32..64])),
+            }),
+        ),
+        Err(err) => error_response(metrics, err.into()),
     }
-    let mut ag_proof = read(format!("{}/../target/ag_proof_{}.bin", dir, req_id))?;
-    let proofbin = ag_proof.split_off(PUBLIC_INPUTS_BYTES);
-    let _public_inputs = ag_proof;
-    let blockhash = &_public_inputs[0..32];
-    let challenge = &_public_inputs[32..64];
-    let public_inputs = _public_inputs[0..PUBLIC_INPUTS_BYTES]
-        .chunks(32)
-        .map(|pi| format!("0x{}", const_hex::encode(pi)))
-        .collect::<Vec<String>>();
-
-    Ok(json!(NoirSafeResult {
-        chain_id: params.chain_id,
-        safe_address: params.safe_address.to_owned(),
-        message_hash: params.message_hash.to_owned(),
-        block_number: anchor,
-        block_hash: format!("0x{}", const_hex::encode(blockhash)),
-        challenge: format!("0x{}", const_hex::encode(challenge)),
-        proof: format!("0x{}", const_hex::encode(proofbin)),
-        public_inputs,
-    }))
 }
 
-#[post("/proof", data = "<params>")]
-async fn proof(params: Json<NoirSafeParams>) -> (Status, Value) {
-    match _proof(params).await {
-        Ok(res) => (Status::Ok, res),
-        Err(err) => {
-            log::error!("{}", err);
-            (
-                Status::BadRequest,
-                json!({
-                    "error": "t(ツ)_/¯ invalid chain id"
-                }),
-            )
-        }
+#[get("/proof/<id>")]
+async fn proof_job(jobs: &State<Jobs>, id: &str) -> (Status, Value) {
+    match jobs.get(id) {
+        Some(job) => job.response(),
+        None => (
+            Status::NotFound,
+            json!({
+                "error": "t(ツ)_/¯ no such job"
+            }),
+        ),
     }
 }
 
@@ -146,17 +373,45 @@ async fn status() -> (Status, Value) {
     (Status::Ok, json!({ "status": "ok" }))
 }
 
+#[get("/vk")]
+async fn verification_key(vk_info: &State<VkInfo>) -> (Status, Value) {
+    (Status::Ok, json!(vk_info.inner()))
+}
+
+#[get("/metrics")]
+async fn metrics(metrics: &State<Arc<Metrics>>) -> (ContentType, String) {
+    (ContentType::Plain, metrics.render())
+}
+
 #[catch(400)]
+fn bad_request(_: &Request) -> Value {
+    json!({
+        "error": "t(ツ)_/¯ invalid request params",
+        "code": "invalid_params"
+    })
+}
+
+#[catch(404)]
 fn not_found(_: &Request) -> Value {
     json!({
-        "error": "t(ツ)_/¯ invalid request params"
+        "error": "t(ツ)_/¯ not found",
+        "code": "not_found"
+    })
+}
+
+#[catch(422)]
+fn unprocessable_entity(_: &Request) -> Value {
+    json!({
+        "error": "t(ツ)_/¯ invalid request params",
+        "code": "invalid_params"
     })
 }
 
 #[catch(500)]
 fn internal_server_error(_: &Request) -> Value {
     json!({
-        "error": "t(ツ)_/¯ invalid storage proof"
+        "error": "t(ツ)_/¯ internal server error",
+        "code": "internal"
     })
 }
 
@@ -190,11 +445,8 @@ impl Fairing for CORS {
 
 #[launch]
 fn rocket() -> _ {
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    init_logger();
     let dir = env::var("CARGO_MANIFEST_DIR").expect("cargo manifest dir");
-    let vk_hash: String = read_to_string(format!("{}/../target/vk_hash", dir)).expect("vk hash");
-    log::info!("vkey hash {}", vk_hash);
 
     let config = Config {
         port: std::env::var("PORT")
@@ -202,12 +454,49 @@ fn rocket() -> _ {
             .unwrap_or(4190),
         address: Ipv4Addr::new(0, 0, 0, 0).into(),
         ip_header: None,
-        limits: Limits::default().limit("json", 256.bytes()),
+        limits: Limits::default()
+            .limit("json", 512.bytes())
+            .limit("verify", VERIFY_LIMIT_KIB.kibibytes()),
         ..Config::release_default()
     };
 
+    let chains_toml = env::var("CHAINS_TOML").unwrap_or(format!("{}/chains.toml", dir));
+    let chains = Registry::load(chains_toml).expect("chain registry");
+    let metrics = Arc::new(Metrics::new().expect("metrics"));
+    let orchestrator = Orchestrator::from_env(PathBuf::from(&dir).join(".."), metrics.clone());
+    let vk_info = orchestrator.vk_info().expect("vk info");
+    log::info!("vkey hash {}", vk_info.vk_hash);
+
+    let cache = Cache::open(
+        format!("{}/../target/noir_safe_cache", dir),
+        &vk_info.vk_hash,
+        vk_info.input_format,
+    )
+    .expect("cache");
+
     rocket::custom(&config)
+        .manage(chains)
+        .manage(Arc::new(cache))
+        .manage(Arc::new(orchestrator))
+        .manage(vk_info)
+        .manage(metrics)
+        .manage(Jobs::new(
+            std::env::var("PROVER_WORKERS")
+                .map(|w| w.parse::<usize>().expect("invalid PROVER_WORKERS"))
+                .unwrap_or(1),
+        ))
         .attach(CORS)
-        .register("/", catchers![internal_server_error, not_found])
-        .mount("/", routes![proof, status])
+        .register(
+            "/",
+            catchers![
+                bad_request,
+                not_found,
+                unprocessable_entity,
+                internal_server_error
+            ],
+        )
+        .mount(
+            "/",
+            routes![proof, proof_job, verify, verification_key, status, metrics],
+        )
 }
@@ -0,0 +1,523 @@
+use crate::{chains::ChainConfig, telemetry::Metrics, vk::VkInfo};
+use anyhow::{anyhow, Result};
+use noir_safe_prelude::{
+    build_inputs_with_header, detect_safe_layout, encode_anchor_header, fetch_snapshot, pin_block,
+    BlockId, InputFormat, InputsFe,
+};
+use rocket::serde::json::from_slice;
+use serde::Serialize;
+use std::{
+    env, fmt,
+    fs::{read, read_to_string, remove_file, write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{process::Command, time::timeout};
+
+/// Default upper bound for any single nargo or bb invocation
+const STAGE_TIMEOUT_SECS: u64 = 600;
+
+/// Input format the shipped anchor circuit derives the challenge in, i.e.
+/// poseidon(safe_address, msg_hash)
+pub const INPUT_FORMAT: InputFormat = InputFormat::V1;
+
+/// A circuit of the pipeline along with the file name prefix of its artifacts
+#[derive(Clone, Copy, Debug)]
+pub struct Circuit {
+    pub tag: &'static str,
+    pub package: &'static str,
+    pub dir: &'static str,
+}
+
+pub const STORAGE_PROOF: Circuit = Circuit {
+    tag: "sp",
+    package: "noir_safe_storage_proof_circuit",
+    dir: "storage_proof",
+};
+pub const ACCOUNT_PROOF: Circuit = Circuit {
+    tag: "ap",
+    package: "noir_safe_account_proof_circuit",
+    dir: "account_proof",
+};
+pub const ANCHOR: Circuit = Circuit {
+    tag: "an",
+    package: "noir_safe_anchor_circuit",
+    dir: "anchor",
+};
+pub const AGGREGATION: Circuit = Circuit {
+    tag: "ag",
+    package: "noir_safe_aggregation_circuit",
+    dir: "aggregation",
+};
+
+#[derive(Debug)]
+pub enum StageFailure {
+    Spawn(std::io::Error),
+    Timeout(Duration),
+    Exit(Option<i32>),
+    Output(String),
+}
+
+/// A failed nargo or bb invocation along with the stderr it produced
+#[derive(Debug)]
+pub struct StageError {
+    pub circuit: &'static str,
+    pub step: &'static str,
+    pub failure: StageFailure,
+    pub stderr: String,
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} ", self.circuit, self.step)?;
+        match &self.failure {
+            StageFailure::Spawn(err) => write!(f, "failed to spawn: {}", err),
+            StageFailure::Timeout(after) => write!(f, "timed out after {:?}", after),
+            StageFailure::Exit(Some(code)) => write!(f, "exited with code {}", code),
+            StageFailure::Exit(None) => write!(f, "killed by signal"),
+            StageFailure::Output(err) => write!(f, "produced invalid output: {}", err),
+        }?;
+        match self.stderr.lines().rev().find(|l| !l.trim().is_empty()) {
+            Some(last) => write!(f, ": {}", last.trim()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for StageError {}
+
+/// Per-request files that are removed once the request is done, even on failure
+struct Artifacts(Vec<PathBuf>);
+
+impl Drop for Artifacts {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            if let Err(err) = remove_file(path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("failed to remove {}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+}
+
+/// Progress milestones reported while proving
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Fetching,
+    ProvingShards,
+    Aggregating,
+}
+
+pub struct Proved {
+    pub block_number: u64,
+    /// Public inputs followed by the aggregated proof as written by bb prove
+    pub ag_proof: Vec<u8>,
+    /// Unpadded anchor header whose hash the aggregated blockhash commits to
+    pub header_rlp: Vec<u8>,
+    /// Encoding of the message hash the challenge was derived from, i.e.
+    /// INPUT_FORMAT
+    pub input_format: InputFormat,
+    pub intermediates: Intermediates,
+}
+
+/// A shard proof along with the verification key hash the aggregation
+/// verified it against
+#[derive(Clone, Debug, Serialize)]
+pub struct ShardProof {
+    pub circuit: &'static str,
+    /// Proof as written by bb prove
+    pub proof: String,
+    /// Output of bb proof_as_fields, i.e. the public inputs followed by the
+    /// proof fields the aggregation takes
+    pub proof_as_fields: Vec<String>,
+    pub public_inputs: Vec<String>,
+    pub vk_hash: String,
+}
+
+impl ShardProof {
+    /// The shard's section of the aggregation prover TOML
+    fn toml(&self) -> String {
+        let proof = self
+            .proof_as_fields
+            .get(self.public_inputs.len()..)
+            .unwrap_or_default();
+        format!(
+            "{}_pi = {}\n{}_proof = {}\n",
+            self.circuit,
+            toml_array(&self.public_inputs),
+            self.circuit,
+            toml_array(proof)
+        )
+    }
+}
+
+/// Everything the aggregated proof was derived from, for auditors to
+/// re-verify each layer
+#[derive(Clone, Debug, Serialize)]
+pub struct Intermediates {
+    pub shards: Vec<ShardProof>,
+    /// Hash of the aggregation verification key, i.e. target/vk_hash
+    pub vk_hash: String,
+    pub aggregation_prover_toml: String,
+}
+
+/// Drives input fetching, the three shard provers and the aggregation
+pub struct Orchestrator {
+    root: PathBuf,
+    nargo: PathBuf,
+    bb: PathBuf,
+    stage_timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Orchestrator {
+    /// Locates nargo and bb at their default install paths unless NARGO or BB
+    /// are set; STAGE_TIMEOUT_SECS overrides the per-invocation timeout
+    pub fn from_env(root: PathBuf, metrics: Arc<Metrics>) -> Self {
+        let home = home::home_dir().expect("home dir");
+        Orchestrator {
+            root,
+            nargo: env::var("NARGO")
+                .map(PathBuf::from)
+                .unwrap_or(home.join(".nargo/bin/nargo")),
+            bb: env::var("BB")
+                .map(PathBuf::from)
+                .unwrap_or(home.join(".bb/bb")),
+            stage_timeout: Duration::from_secs(
+                env::var("STAGE_TIMEOUT_SECS")
+                    .map(|s| s.parse::<u64>().expect("invalid STAGE_TIMEOUT_SECS"))
+                    .unwrap_or(STAGE_TIMEOUT_SECS),
+            ),
+            metrics,
+        }
+    }
+
+    /// Verification keys of the compiled circuits along with the nargo and
+    /// bb versions the server proves with
+    pub fn vk_info(&self) -> Result<VkInfo> {
+        VkInfo::load(&self.root, &self.nargo, &self.bb, INPUT_FORMAT)
+    }
+
+    fn target(&self, file: String) -> PathBuf {
+        self.root.join("target").join(file)
+    }
+
+    fn prover_toml(&self, circuit: Circuit, req_id: &str) -> PathBuf {
+        self.root
+            .join("circuits")
+            .join(circuit.dir)
+            .join(format!("{}_prover_{}.toml", circuit.tag, req_id))
+    }
+
+    fn witness(&self, circuit: Circuit, req_id: &str) -> PathBuf {
+        self.target(format!("{}_witness_{}.gz", circuit.tag, req_id))
+    }
+
+    fn proof(&self, circuit: Circuit, req_id: &str) -> PathBuf {
+        self.target(format!("{}_proof_{}.bin", circuit.tag, req_id))
+    }
+
+    pub async fn prove(
+        &self,
+        req_id: &str,
+        chain: &ChainConfig,
+        safe_address: [u8; 20],
+        msg_hash: [u8; 32],
+        block: BlockId,
+        progress: &(dyn Fn(Stage) + Send + Sync),
+    ) -> Result<Proved> {
+        let _artifacts = Artifacts(
+            [STORAGE_PROOF, ACCOUNT_PROOF, ANCHOR, AGGREGATION]
+                .into_iter()
+                .flat_map(|c| {
+                    [
+                        self.prover_toml(c, req_id),
+                        self.witness(c, req_id),
+                        self.proof(c, req_id),
+                    ]
+                })
+                .collect(),
+        );
+
+        progress(Stage::Fetching);
+        let (block_number, inputs) = chain
+            .with_failover(|rpc| {
+                let metrics = &self.metrics;
+                async move {
+                    let (safe_address, msg_hash) = (safe_address.into(), msg_hash.into());
+                    let (spec, snapshot) = metrics
+                        .span(req_id, "rpc_fetch", "", async {
+                            // Pin tags to a number so the layout is that of
+                            // the block the proof is fetched at
+                            let block = pin_block(&rpc, block).await?;
+                            let layout = detect_safe_layout(&rpc, safe_address, block).await?;
+                            let spec = chain.spec(layout);
+                            let snapshot =
+                                fetch_snapshot(&rpc, safe_address, &[msg_hash], block, &spec)
+                                    .await?;
+                            anyhow::Ok((spec, snapshot))
+                        })
+                        .await?;
+                    let header_rlp = metrics
+                        .span(req_id, "header_encoding", "", async {
+                            encode_anchor_header(&snapshot.block, &spec)
+                        })
+                        .await?;
+                    // Preflight and trie proof preprocessing
+                    metrics
+                        .span(req_id, "preprocessing", "", async {
+                            build_inputs_with_header(
+                                &snapshot.block,
+                                header_rlp,
+                                &snapshot.proof,
+                                safe_address,
+                                msg_hash,
+                                &spec,
+                            )
+                        })
+                        .await
+                }
+            })
+            .await?;
+        let header_rlp = inputs.header().to_vec();
+        let inputs_fe = InputsFe::new(inputs, INPUT_FORMAT);
+        let prover_toml = toml::to_string(&inputs_fe)?;
+        write(self.prover_toml(STORAGE_PROOF, req_id), &prover_toml)?;
+        write(self.prover_toml(ACCOUNT_PROOF, req_id), &prover_toml)?;
+        write(
+            self.prover_toml(ANCHOR, req_id),
+            format!("{}\nblocknumber = {}", prover_toml, block_number),
+        )?;
+
+        progress(Stage::ProvingShards);
+        let vk_toml = read_to_string(self.target("vk.toml".to_string()))?;
+        let vks = toml::from_str::<toml::Table>(&vk_toml)?;
+        let an_pi = [inputs_fe.blockhash.clone(), inputs_fe.challenge.clone()];
+        let (sp, ap, an) = self
+            .metrics
+            .span(req_id, "shards", "", async {
+                tokio::try_join!(
+                    self.shard(STORAGE_PROOF, req_id, &[], &vks),
+                    self.shard(ACCOUNT_PROOF, req_id, &[], &vks),
+                    self.shard(ANCHOR, req_id, &an_pi, &vks),
+                )
+            })
+            .await?;
+
+        progress(Stage::Aggregating);
+        let aggregation_prover_toml =
+            format!("{}\n{}{}{}", vk_toml, sp.toml(), ap.toml(), an.toml());
+        self.metrics
+            .span(req_id, "aggregation", AGGREGATION.tag, async {
+                write(
+                    self.prover_toml(AGGREGATION, req_id),
+                    &aggregation_prover_toml,
+                )?;
+                self.execute_and_prove(AGGREGATION, req_id).await?;
+                anyhow::Ok(())
+            })
+            .await?;
+
+        Ok(Proved {
+            block_number,
+            ag_proof: read(self.proof(AGGREGATION, req_id))?,
+            header_rlp,
+            input_format: INPUT_FORMAT,
+            intermediates: Intermediates {
+                shards: vec![sp, ap, an],
+                vk_hash: read_to_string(self.target("vk_hash".to_string()))?
+                    .trim()
+                    .to_string(),
+                aggregation_prover_toml,
+            },
+        })
+    }
+
+    /// Verifies an aggregated proof, i.e. its public inputs followed by the
+    /// proof as written by bb prove, against target/ag_vk
+    pub async fn verify(&self, req_id: &str, ag_proof: &[u8]) -> Result<bool> {
+        let proof = self.target(format!("ag_verify_{}.bin", req_id));
+        let _artifacts = Artifacts(vec![proof.clone()]);
+        write(&proof, ag_proof)?;
+        let verified = self
+            .run(
+                req_id,
+                AGGREGATION.tag,
+                "bb verify",
+                &self.bb,
+                &[
+                    "verify".as_ref(),
+                    "-p".as_ref(),
+                    proof.as_os_str(),
+                    "-k".as_ref(),
+                    self.target("ag_vk".to_string()).as_os_str(),
+                ],
+            )
+            .await;
+        match verified {
+            Ok(_) => Ok(true),
+            // bb verify exits nonzero on a proof that does not verify
+            Err(StageError {
+                failure: StageFailure::Exit(_),
+                ..
+            }) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Proves a shard and reads back its proof
+    async fn shard(
+        &self,
+        circuit: Circuit,
+        req_id: &str,
+        public_inputs: &[String],
+        vks: &toml::Table,
+    ) -> Result<ShardProof> {
+        self.execute_and_prove(circuit, req_id).await?;
+        let stdout = self
+            .run(
+                req_id,
+                circuit.tag,
+                "bb proof_as_fields",
+                &self.bb,
+                &[
+                    "proof_as_fields".as_ref(),
+                    "-p".as_ref(),
+                    self.proof(circuit, req_id).as_os_str(),
+                    "-k".as_ref(),
+                    self.target(format!("{}_vk", circuit.tag)).as_os_str(),
+                    "-o".as_ref(),
+                    "-".as_ref(),
+                ],
+            )
+            .await?;
+        // proof_as_fields leads with the public inputs which the aggregation takes separately
+        let proof_as_fields = from_slice::<Vec<String>>(&stdout).map_err(|err| StageError {
+            circuit: circuit.tag,
+            step: "bb proof_as_fields",
+            failure: StageFailure::Output(err.to_string()),
+            stderr: String::new(),
+        })?;
+        let vk_hash = vks
+            .get(&format!("{}_vk_hash", circuit.tag))
+            .and_then(|hash| hash.as_str())
+            .ok_or_else(|| anyhow!("vk.toml lacks {}_vk_hash", circuit.tag))?;
+        Ok(ShardProof {
+            circuit: circuit.tag,
+            proof: format!(
+                "0x{}",
+                const_hex::encode(read(self.proof(circuit, req_id))?)
+            ),
+            proof_as_fields,
+            public_inputs: public_inputs.to_vec(),
+            vk_hash: vk_hash.to_string(),
+        })
+    }
+
+    async fn execute_and_prove(&self, circuit: Circuit, req_id: &str) -> Result<(), StageError> {
+        let prover_name = format!("{}_prover_{}", circuit.tag, req_id);
+        let witness_name = format!("{}_witness_{}", circuit.tag, req_id);
+        self.run(
+            req_id,
+            circuit.tag,
+            "nargo execute",
+            &self.nargo,
+            &[
+                "execute".as_ref(),
+                "--package".as_ref(),
+                circuit.package.as_ref(),
+                "--prover-name".as_ref(),
+                prover_name.as_ref(),
+                witness_name.as_ref(),
+            ],
+        )
+        .await?;
+        self.run(
+            req_id,
+            circuit.tag,
+            "bb prove",
+            &self.bb,
+            &[
+                "prove".as_ref(),
+                "-b".as_ref(),
+                self.target(format!("{}_circuit", circuit.tag)).as_os_str(),
+                "-w".as_ref(),
+                self.witness(circuit, req_id).as_os_str(),
+                "-o".as_ref(),
+                self.proof(circuit, req_id).as_os_str(),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Runs a tracked child process that is killed if it outlives the stage
+    /// timeout and records its duration as a span of the request
+    async fn run(
+        &self,
+        req_id: &str,
+        circuit: &'static str,
+        step: &'static str,
+        program: &Path,
+        args: &[&std::ffi::OsStr],
+    ) -> Result<Vec<u8>, StageError> {
+        let stage = step.replace(' ', "_");
+        self.metrics
+            .span(
+                req_id,
+                &stage,
+                circuit,
+                self.spawn(circuit, step, program, args),
+            )
+            .await
+    }
+
+    async fn spawn(
+        &self,
+        circuit: &'static str,
+        step: &'static str,
+        program: &Path,
+        args: &[&std::ffi::OsStr],
+    ) -> Result<Vec<u8>, StageError> {
+        let stage_error = |failure, stderr| StageError {
+            circuit,
+            step,
+            failure,
+            stderr,
+        };
+        let child = Command::new(program)
+            .args(args)
+            .current_dir(&self.root)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| stage_error(StageFailure::Spawn(err), String::new()))?;
+        let output = timeout(self.stage_timeout, child.wait_with_output())
+            .await
+            .map_err(|_| stage_error(StageFailure::Timeout(self.stage_timeout), String::new()))?
+            .map_err(|err| stage_error(StageFailure::Spawn(err), String::new()))?;
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        if !output.status.success() {
+            log::error!("{} {} stderr:\n{}", circuit, step, stderr);
+            return Err(stage_error(
+                StageFailure::Exit(output.status.code()),
+                stderr,
+            ));
+        }
+        log::debug!("{} {} stderr:\n{}", circuit, step, stderr);
+        Ok(output.stdout)
+    }
+}
+
+fn toml_array(items: &[String]) -> String {
+    let quoted = items
+        .iter()
+        .map(|item| format!("\"{}\"", item))
+        .collect::<Vec<String>>();
+    format!("[{}]", quoted.join(", "))
+}
@@ -0,0 +1,183 @@
+use crate::error::ApiError;
+use noir_safe_prelude::keccak256;
+
+/// Decodes a 0x-prefixed hex string of exactly `N` bytes
+pub fn parse_0x_hex<const N: usize>(field: &'static str, s: &str) -> Result<[u8; N], ApiError> {
+    let invalid = |reason: String| ApiError::InvalidParams { field, reason };
+    let digits = s
+        .strip_prefix("0x")
+        .ok_or_else(|| invalid("missing 0x prefix".to_string()))?;
+    if digits.len() != 2 * N {
+        return Err(invalid(format!(
+            "expected {} hex digits got {}",
+            2 * N,
+            digits.len()
+        )));
+    }
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(invalid(format!("invalid hex digit {:?}", c)));
+    }
+    const_hex::decode_to_array::<&str, N>(digits).map_err(|e| invalid(e.to_string()))
+}
+
+/// Decodes a 0x-prefixed hex string of any nonzero number of bytes
+pub fn parse_0x_bytes(field: &'static str, s: &str) -> Result<Vec<u8>, ApiError> {
+    let invalid = |reason: String| ApiError::InvalidParams { field, reason };
+    let digits = s
+        .strip_prefix("0x")
+        .ok_or_else(|| invalid("missing 0x prefix".to_string()))?;
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return Err(invalid(format!(
+            "expected a nonzero even number of hex digits got {}",
+            digits.len()
+        )));
+    }
+    const_hex::decode(digits).map_err(|e| invalid(e.to_string()))
+}
+
+/// Decodes an address; mixed-case addresses must carry a valid EIP-55
+/// checksum while all lower- or uppercase ones are taken as is
+pub fn parse_address(field: &'static str, s: &str) -> Result<[u8; 20], ApiError> {
+    let address = parse_0x_hex::<20>(field, s)?;
+    let digits = &s[2..];
+    let mixed_case = digits.chars().any(|c| c.is_ascii_lowercase())
+        && digits.chars().any(|c| c.is_ascii_uppercase());
+    if mixed_case && digits != &to_checksum(&address)[2..] {
+        return Err(ApiError::InvalidParams {
+            field,
+            reason: "invalid EIP-55 checksum".to_string(),
+        });
+    }
+    Ok(address)
+}
+
+/// EIP-55 mixed-case encoding of an address
+pub fn to_checksum(address: &[u8; 20]) -> String {
+    let lower = const_hex::encode(address);
+    let hash = const_hex::encode(keccak256(lower.as_bytes()));
+    let checksummed = lower
+        .chars()
+        .zip(hash.chars())
+        .map(|(c, h)| match h.to_digit(16) {
+            Some(n) if n >= 8 => c.to_ascii_uppercase(),
+            _ => c,
+        })
+        .collect::<String>();
+    format!("0x{}", checksummed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test vectors of EIP-55
+    const CHECKSUMMED: [&str; 4] = [
+        "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+        "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+        "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+        "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+    ];
+
+    fn reason(result: Result<impl std::fmt::Debug, ApiError>) -> String {
+        match result {
+            Err(ApiError::InvalidParams { field, reason }) => {
+                assert_eq!(field, "safe_address");
+                reason
+            }
+            other => panic!("expected invalid params got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn checksum_vectors() {
+        for address in CHECKSUMMED {
+            let parsed = parse_address("safe_address", address).unwrap();
+            assert_eq!(to_checksum(&parsed), address);
+        }
+    }
+
+    #[test]
+    fn uniform_case_is_taken_as_is() {
+        for address in [
+            "0x52908400098527886E0F7030069857D2E4169EE7",
+            "0x8617E340B3D01FA5F11F306F4090FD50E238070D",
+            "0xde709f2102306220921060314715629080e2fb77",
+            "0x27b1fdb04752bbc536007a920d24acb045561c26",
+        ] {
+            let parsed = parse_address("safe_address", address).unwrap();
+            assert_eq!(to_checksum(&parsed).to_lowercase(), address.to_lowercase());
+        }
+        let lower = CHECKSUMMED[0].to_lowercase();
+        let upper = format!("0x{}", CHECKSUMMED[0][2..].to_uppercase());
+        assert_eq!(
+            parse_address("safe_address", &lower).unwrap(),
+            parse_address("safe_address", &upper).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_checksum() {
+        for address in CHECKSUMMED {
+            // flip the case of the first letter
+            let i = address[2..]
+                .find(|c: char| c.is_ascii_alphabetic())
+                .unwrap()
+                + 2;
+            let mut flipped = address.to_string();
+            let c = address.as_bytes()[i] as char;
+            let swapped = if c.is_ascii_lowercase() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            };
+            flipped.replace_range(i..i + 1, &swapped.to_string());
+            assert_eq!(
+                reason(parse_address("safe_address", &flipped)),
+                "invalid EIP-55 checksum"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_hex() {
+        let address = &CHECKSUMMED[0][2..];
+        assert_eq!(
+            reason(parse_address("safe_address", address)),
+            "missing 0x prefix"
+        );
+        assert_eq!(
+            reason(parse_address("safe_address", &CHECKSUMMED[0][..41])),
+            "expected 40 hex digits got 39"
+        );
+        assert_eq!(
+            reason(parse_address(
+                "safe_address",
+                &format!("{}00", CHECKSUMMED[0])
+            )),
+            "expected 40 hex digits got 42"
+        );
+        assert_eq!(
+            reason(parse_address(
+                "safe_address",
+                &format!("0x{}g", &address[1..])
+            )),
+            "invalid hex digit 'g'"
+        );
+        assert_eq!(
+            reason(parse_0x_hex::<32>("safe_address", "0X00")),
+            "missing 0x prefix"
+        );
+        assert_eq!(
+            reason(parse_0x_bytes("safe_address", "0x")),
+            "expected a nonzero even number of hex digits got 0"
+        );
+        assert_eq!(
+            reason(parse_0x_bytes("safe_address", "0xabc")),
+            "expected a nonzero even number of hex digits got 3"
+        );
+        assert_eq!(
+            parse_0x_bytes("safe_address", "0xc0ffee").unwrap(),
+            vec![0xc0, 0xff, 0xee]
+        );
+    }
+}
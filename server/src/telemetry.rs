@@ -0,0 +1,132 @@
+use crate::get_epoch_millis;
+use prometheus::{
+    histogram_opts, opts, Encoder, HistogramVec, IntCounterVec, Registry, TextEncoder,
+};
+use rocket::serde::json::{serde_json::Map, Value};
+use std::{env, fmt, future::Future, io::Write, time::Instant};
+
+/// Buckets in seconds spanning millisecond RPC calls up to minutes of proving
+const STAGE_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+
+/// Prometheus metrics of the proving pipeline
+pub struct Metrics {
+    registry: Registry,
+    stage_seconds: HistogramVec,
+    failures: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("noir_safe".to_string()), None)?;
+        let stage_seconds = HistogramVec::new(
+            histogram_opts!(
+                "stage_duration_seconds",
+                "Duration of each proving stage",
+                STAGE_BUCKETS.to_vec()
+            ),
+            &["stage", "circuit", "outcome"],
+        )?;
+        let failures = IntCounterVec::new(
+            opts!("failures_total", "Failed requests by error code"),
+            &["code"],
+        )?;
+        registry.register(Box::new(stage_seconds.clone()))?;
+        registry.register(Box::new(failures.clone()))?;
+        Ok(Metrics {
+            registry,
+            stage_seconds,
+            failures,
+        })
+    }
+
+    /// Runs a stage of a request, e.g. a circuit's `bb prove`, and records
+    /// its duration and outcome both as a metric and a log line
+    pub async fn span<T, E, F>(
+        &self,
+        req_id: &str,
+        stage: &str,
+        circuit: &str,
+        f: F,
+    ) -> Result<T, E>
+    where
+        E: fmt::Display,
+        F: Future<Output = Result<T, E>>,
+    {
+        let start = Instant::now();
+        let res = f.await;
+        let elapsed = start.elapsed();
+        let duration_ms = elapsed.as_millis() as u64;
+        let outcome = if res.is_ok() { "ok" } else { "failed" };
+        self.stage_seconds
+            .with_label_values(&[stage, circuit, outcome])
+            .observe(elapsed.as_secs_f64());
+        let label = match circuit {
+            "" => stage.to_string(),
+            circuit => format!("{} {}", circuit, stage),
+        };
+        match &res {
+            Ok(_) => log::info!(
+                req_id = req_id, stage = stage, circuit = circuit, duration_ms = duration_ms;
+                "{} done in {}ms", label, duration_ms
+            ),
+            Err(err) => log::warn!(
+                req_id = req_id, stage = stage, circuit = circuit, duration_ms = duration_ms;
+                "{} failed after {}ms: {}", label, duration_ms, err
+            ),
+        }
+        res
+    }
+
+    pub fn failure(&self, code: &str) {
+        self.failures.with_label_values(&[code]).inc();
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            log::error!("encoding metrics failed: {}", err);
+        }
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+}
+
+/// Collects the key-values of a log record into a JSON object
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = match value.to_u64() {
+            Some(n) => Value::from(n),
+            None => Value::from(value.to_string()),
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+/// Logs at info level by default, as one JSON object per line if LOG_FORMAT
+/// is json and in env_logger's format otherwise
+pub fn init_logger() {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if env::var("LOG_FORMAT").as_deref() == Ok("json") {
+        builder.format(|buf, record| {
+            let mut fields = Map::new();
+            let ts = get_epoch_millis() as u64;
+            fields.insert("ts".to_string(), Value::from(ts));
+            fields.insert("level".to_string(), Value::from(record.level().as_str()));
+            fields.insert("target".to_string(), Value::from(record.target()));
+            fields.insert("msg".to_string(), Value::from(record.args().to_string()));
+            let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+            writeln!(buf, "{}", Value::Object(fields))
+        });
+    }
+    builder.init();
+}
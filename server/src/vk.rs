@@ -0,0 +1,147 @@
+use crate::orchestrator::{Circuit, ACCOUNT_PROOF, AGGREGATION, ANCHOR, STORAGE_PROOF};
+use anyhow::{anyhow, Result};
+use noir_safe_prelude::{keccak256, InputFormat};
+use rocket::serde::json::{from_str, Value};
+use serde::Serialize;
+use std::{
+    fs::read_to_string,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// Verifier artifact written by scripts/compile.sh
+const VERIFIER_ARTIFACT: &str = "target/forge/UltraVerifier.sol/UltraVerifier.json";
+
+/// A circuit's package along with the hash of its verification key
+#[derive(Clone, Debug, Serialize)]
+pub struct CircuitInfo {
+    pub circuit: &'static str,
+    pub package: &'static str,
+    /// Package version in Nargo.toml, if any
+    pub version: Option<String>,
+    /// compiler_version requirement in Nargo.toml
+    pub compiler_version: Option<String>,
+    /// noir_version the compiled circuit in target/ was built with
+    pub noir_version: Option<String>,
+    pub vk_hash: String,
+}
+
+/// Hashes of the Solidity verifier of the aggregation circuit
+#[derive(Clone, Debug, Serialize)]
+pub struct VerifierInfo {
+    /// keccak256 of UltraVerifier.sol
+    pub source_hash: String,
+    /// keccak256 of the deployed bytecode, i.e. what EXTCODEHASH returns for
+    /// a verifier compiled by scripts/compile.sh; None if it was not compiled
+    pub bytecode_hash: Option<String>,
+}
+
+/// Verification keys and toolchain the server proves with
+#[derive(Clone, Debug, Serialize)]
+pub struct VkInfo {
+    /// Hash of the aggregation verification key
+    pub vk_hash: String,
+    pub circuits: Vec<CircuitInfo>,
+    pub nargo_version: Option<String>,
+    pub bb_version: Option<String>,
+    /// Encoding of the message hash the challenge is derived from
+    pub input_format: InputFormat,
+    pub verifier: VerifierInfo,
+}
+
+impl VkInfo {
+    /// Reads the compiled circuits and verifier below the repo root and asks
+    /// nargo and bb for their versions
+    pub fn load(root: &Path, nargo: &Path, bb: &Path, input_format: InputFormat) -> Result<Self> {
+        let target = root.join("target");
+        let vk_hash = read_to_string(target.join("vk_hash"))?.trim().to_string();
+        let vks = toml::from_str::<toml::Table>(&read_to_string(target.join("vk.toml"))?)?;
+        let circuits = [STORAGE_PROOF, ACCOUNT_PROOF, ANCHOR, AGGREGATION]
+            .into_iter()
+            .map(|circuit| {
+                let vk_hash = match circuit.tag {
+                    "ag" => vk_hash.clone(),
+                    tag => vks
+                        .get(&format!("{}_vk_hash", tag))
+                        .and_then(|hash| hash.as_str())
+                        .ok_or_else(|| anyhow!("vk.toml lacks {}_vk_hash", tag))?
+                        .to_string(),
+                };
+                circuit_info(root, circuit, vk_hash)
+            })
+            .collect::<Result<Vec<CircuitInfo>>>()?;
+        let source = read_to_string(root.join("UltraVerifier.sol"))?;
+        let bytecode_hash = read_to_string(root.join(VERIFIER_ARTIFACT))
+            .ok()
+            .and_then(|artifact| from_str::<Value>(&artifact).ok())
+            .and_then(|artifact| {
+                let bytecode = artifact["deployedBytecode"]["object"].as_str()?;
+                const_hex::decode(bytecode).ok()
+            })
+            .map(|bytecode| format!("0x{}", const_hex::encode(keccak256(bytecode))));
+        Ok(VkInfo {
+            vk_hash,
+            circuits,
+            nargo_version: tool_version(nargo),
+            bb_version: tool_version(bb),
+            input_format,
+            verifier: VerifierInfo {
+                source_hash: format!("0x{}", const_hex::encode(keccak256(source.as_bytes()))),
+                bytecode_hash,
+            },
+        })
+    }
+}
+
+fn circuit_info(root: &Path, circuit: Circuit, vk_hash: String) -> Result<CircuitInfo> {
+    let nargo_toml = root.join("circuits").join(circuit.dir).join("Nargo.toml");
+    let manifest = toml::from_str::<toml::Table>(&read_to_string(nargo_toml)?)?;
+    let package = |key: &str| {
+        manifest
+            .get("package")
+            .and_then(|package| package.get(key))
+            .and_then(|value| value.as_str())
+            .map(String::from)
+    };
+    let compiled = root
+        .join("target")
+        .join(format!("{}.json", circuit.package));
+    let noir_version = read_to_string(compiled)
+        .ok()
+        .and_then(|compiled| from_str::<Value>(&compiled).ok())
+        .and_then(|compiled| compiled["noir_version"].as_str().map(String::from));
+    Ok(CircuitInfo {
+        circuit: circuit.tag,
+        package: circuit.package,
+        version: package("version"),
+        compiler_version: package("compiler_version"),
+        noir_version,
+        vk_hash,
+    })
+}
+
+/// First line of `<program> --version`, if it runs
+fn tool_version(program: &Path) -> Option<String> {
+    let output = Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .map(|line| line.trim().to_string()),
+        Ok(output) => {
+            log::warn!(
+                "{} --version exited with {}",
+                program.display(),
+                output.status
+            );
+            None
+        }
+        Err(err) => {
+            log::warn!("{} --version failed: {}", program.display(), err);
+            None
+        }
+    }
+}
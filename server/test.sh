@@ -15,6 +15,18 @@ params="$(cat << EOF
 EOF
 )"
 
+await_job() {
+  # usage: await_job "$job_id" "$resp_body" "$resp_head"
+  while true; do
+    curl -sS -D $3 http:/localhost:4190/proof/$1 > $2
+    status="$(jq -r '.status' $2)"
+    if [[ "$status" == "done" ]] || [[ "$status" == "failed" ]]; then
+      break
+    fi
+    sleep 5
+  done
+}
+
 test_proving_ok() {
   printf "test_proving_ok\n"
 
@@ -28,17 +40,35 @@ test_proving_ok() {
     -d "$params" \
   > $resp_body
 
+  assert_status $resp_head 202
+  await_job "$(jq -r '.id' $resp_body)" $resp_body $resp_head
   assert_status $resp_head 200
+  assert_equal "$(jq -r '.status' $resp_body)" 'done'
 
-  block_number=$(jq -r '.block_number' $resp_body)
-  block_hash=$(jq -r '.block_hash' $resp_body)
-  challenge=$(jq -r '.challenge' $resp_body)
-  proof=$(jq -r '.proof' $resp_body)
+  block_number=$(jq -r '.result.block_number' $resp_body)
+  block_hash=$(jq -r '.result.block_hash' $resp_body)
+  anchor_block_hash=$(jq -r '.result.anchor_block_hash' $resp_body)
+  challenge=$(jq -r '.result.challenge' $resp_body)
+  proof=$(jq -r '.result.proof' $resp_body)
 
   assert_gt $block_number 33119702
   assert_match $block_hash '^0x[a-f0-9]{64}$'
+  assert_match $anchor_block_hash '^0x[a-f0-9]{64}$'
   assert_match $challenge '^0x[a-f0-9]{64}$'
   assert_match $proof '^0x[a-f0-9]+$'
+
+  verify_body=$(mktemp)
+  jq -c '.result' $resp_body | curl \
+    -sS \
+    -D $resp_head \
+    http:/localhost:4190/verify \
+    -d @- \
+  > $verify_body
+
+  assert_status $resp_head 200
+  assert_equal "$(jq -r '.valid' $verify_body)" 'true'
+  assert_equal "$(jq -r '.block_hash' $verify_body)" "$block_hash"
+  assert_equal "$(jq -r '.challenge' $verify_body)" "$challenge"
 }
 
 test_proving_not_ok() {
@@ -56,9 +86,12 @@ test_proving_not_ok() {
     -d "$not_ok_params" \
   > $resp_body
 
-  assert_status $resp_head 500
-  err="$(jq -r '.error' $resp_body)"
-  assert_equal "$err" 't(ツ)_/¯ invalid storage proof'
+  assert_status $resp_head 202
+  await_job "$(jq -r '.id' $resp_body)" $resp_body $resp_head
+  assert_status $resp_head 404
+  assert_equal "$(jq -r '.status' $resp_body)" 'failed'
+  code="$(jq -r '.error.code' $resp_body)"
+  assert_equal "$code" 'message_not_signed'
 }
 
 test_wrong_chain_id() {
@@ -79,6 +112,68 @@ test_wrong_chain_id() {
   assert_status $resp_head 400
   err="$(jq -r '.error' $resp_body)"
   assert_equal "$err" 't(ツ)_/¯ invalid chain id'
+  code="$(jq -r '.code' $resp_body)"
+  assert_equal "$code" 'unsupported_chain'
+}
+
+test_invalid_block() {
+  printf "test_invalid_block\n"
+
+  resp_head=$(mktemp)
+  resp_body=$(mktemp)
+  invalid_params="$(echo "$params" | jq -c '. + { "block": "yesterday" }')"
+
+  curl \
+    -sS \
+    -D $resp_head \
+    http:/localhost:4190/proof \
+    -d "$invalid_params" \
+  > $resp_body
+
+  assert_status $resp_head 400
+  code="$(jq -r '.code' $resp_body)"
+  assert_equal "$code" 'invalid_params'
+}
+
+test_bad_checksum() {
+  printf "test_bad_checksum\n"
+
+  resp_head=$(mktemp)
+  resp_body=$(mktemp)
+  # flip the case of a single address character
+  bad_params="$(echo "$params" | sed 's/0x38Ba/0x38bA/')"
+
+  curl \
+    -sS \
+    -D $resp_head \
+    http:/localhost:4190/proof \
+    -d "$bad_params" \
+  > $resp_body
+
+  assert_status $resp_head 400
+  field="$(jq -r '.field' $resp_body)"
+  assert_equal "$field" 'safe_address'
+}
+
+test_verify_bad_layout() {
+  printf "test_verify_bad_layout\n"
+
+  resp_head=$(mktemp)
+  resp_body=$(mktemp)
+  # blockhash and challenge without the accumulator limbs
+  zero="0x$(printf '0%.0s' {1..64})"
+  bad_params="{\"proof\": \"0x00\", \"public_inputs\": [\"$zero\", \"$zero\"]}"
+
+  curl \
+    -sS \
+    -D $resp_head \
+    http:/localhost:4190/verify \
+    -d "$bad_params" \
+  > $resp_body
+
+  assert_status $resp_head 400
+  field="$(jq -r '.field' $resp_body)"
+  assert_equal "$field" 'public_inputs'
 }
 
 test_status() {
@@ -98,7 +193,48 @@ test_status() {
   assert_equal "$status" 'ok'
 }
 
+test_vk() {
+  printf "test_vk\n"
+
+  resp_head=$(mktemp)
+  resp_body=$(mktemp)
+
+  curl \
+    -sS \
+    -D $resp_head \
+    http:/localhost:4190/vk \
+  > $resp_body
+
+  assert_status $resp_head 200
+  assert_equal "$(jq -r '.vk_hash' $resp_body)" "$(cat $d/target/vk_hash)"
+  assert_equal "$(jq -r '.circuits | length' $resp_body)" 4
+  assert_match "$(jq -r '.verifier.source_hash' $resp_body)" '^0x[a-f0-9]{64}$'
+}
+
+test_metrics() {
+  printf "test_metrics\n"
+
+  resp_head=$(mktemp)
+  resp_body=$(mktemp)
+
+  curl \
+    -sS \
+    -D $resp_head \
+    http:/localhost:4190/metrics \
+  > $resp_body
+
+  assert_status $resp_head 200
+  # test_wrong_chain_id ran before
+  failures="$(grep -c '^noir_safe_failures_total{code="unsupported_chain"}' $resp_body)"
+  assert_equal "$failures" 1
+}
+
 test_proving_ok
-# test_proving_not_ok #FIXME
+test_proving_not_ok
 test_wrong_chain_id
-test_status
\ No newline at end of file
+test_invalid_block
+test_bad_checksum
+test_verify_bad_layout
+test_status
+test_vk
+test_metrics
\ No newline at end of file